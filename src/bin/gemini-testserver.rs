@@ -0,0 +1,276 @@
+extern crate openssl;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{SslAcceptor, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::X509;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+extern crate percent_encoding;
+use percent_encoding::percent_decode_str;
+
+extern crate url;
+use url::Url;
+
+use ruostepurkki::{certificates, identities};
+
+const USAGE: &str = "Usage: gemini-testserver [options]
+
+Options:
+    -r, --root DIR        serve files from DIR (default: current directory)
+    -p, --port PORT       listen on PORT on localhost, 0 picking a free one (default: 1965)
+        --rotate-certs    present a newly generated certificate on every connection
+    -h, --help            show this help
+
+The address is printed on the first line of output once the server is listening.
+
+Besides the files under the root, these paths are scripted:
+    /status/CODE[?META]     respond with the given status code and meta
+    /slow/LINES/MILLIS      send LINES lines of text, pausing MILLIS ms before each
+    /huge/BYTES             send a text body of BYTES bytes
+    /private                require a client certificate and show its fingerprint";
+
+/// Longest request line accepted, including the URL and CRLF
+const MAX_REQUEST_LENGTH: usize = 1024 + 2;
+
+struct Options {
+    root: PathBuf,
+    port: u16,
+    rotate_certs: bool
+}
+
+fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        root: PathBuf::from("."),
+        port: 1965,
+        rotate_certs: false
+    };
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => { return Ok(None); }
+            "-r" | "--root" => {
+                match iter.next() {
+                    Some(dir) => { options.root = PathBuf::from(dir); }
+                    None => { return Err(format!("{} needs a directory", arg)); }
+                }
+            }
+            "-p" | "--port" => {
+                match iter.next().map(|p| p.parse::<u16>()) {
+                    Some(Ok(port)) => { options.port = port; }
+                    _ => { return Err(format!("{} needs a port number", arg)); }
+                }
+            }
+            "--rotate-certs" => { options.rotate_certs = true; }
+            _ => { return Err(format!("Unknown argument: {}", arg)); }
+        }
+    }
+
+    Ok(Some(options))
+}
+
+fn acceptor(certificate: &X509, key: &PKey<Private>) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate(certificate)?;
+    builder.set_private_key(key)?;
+    // Ask for a client certificate but take any, Gemini clients use self-signed ones
+    builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+    Ok(builder.build())
+}
+
+fn generated_acceptor() -> Result<SslAcceptor, ErrorStack> {
+    let (certificate, key) = identities::generate_self_signed("localhost")?;
+    acceptor(&certificate, &key)
+}
+
+fn read_request(stream: &mut SslStream<TcpStream>) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut byte = [0u8; 1];
+
+    while !request.ends_with(b"\r\n") {
+        if request.len() >= MAX_REQUEST_LENGTH || stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no complete request line"));
+        }
+        request.push(byte[0]);
+    }
+    request.truncate(request.len() - 2);
+
+    String::from_utf8(request).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_header(stream: &mut SslStream<TcpStream>, code: &str, meta: &str) -> io::Result<()> {
+    if meta.is_empty() {
+        write!(stream, "{}\r\n", code)
+    } else {
+        write!(stream, "{} {}\r\n", code, meta)
+    }
+}
+
+fn respond(stream: &mut SslStream<TcpStream>, request: &str, root: &Path) -> io::Result<()> {
+    let url = match Url::parse(request) {
+        Ok(u) if u.scheme() == "gemini" => u,
+        _ => { return write_header(stream, "59", "Bad request"); }
+    };
+
+    let segments = url.path().trim_start_matches('/').split('/').collect::<Vec<&str>>();
+    match segments.as_slice() {
+        ["status", code] => {
+            let meta = url.query().map(|q| percent_decode_str(q).decode_utf8_lossy().to_string());
+            write_header(stream, code, meta.as_deref().unwrap_or(""))
+        }
+        ["slow", lines, millis] => {
+            let (lines, millis) = match (lines.parse::<usize>(), millis.parse::<u64>()) {
+                (Ok(l), Ok(m)) => (l, m),
+                _ => { return write_header(stream, "59", "Expected /slow/LINES/MILLIS"); }
+            };
+            write_header(stream, "20", "text/plain")?;
+            for i in 0..lines {
+                thread::sleep(Duration::from_millis(millis));
+                writeln!(stream, "Line {}", i + 1)?;
+                stream.flush()?;
+            }
+            Ok(())
+        }
+        ["huge", bytes] => {
+            let bytes = match bytes.parse::<usize>() {
+                Ok(b) => b,
+                Err(_) => { return write_header(stream, "59", "Expected /huge/BYTES"); }
+            };
+            write_header(stream, "20", "text/plain")?;
+            let chunk = [b'x'; 8192];
+            let mut left = bytes;
+            while left > 0 {
+                let n = std::cmp::min(left, chunk.len());
+                stream.write_all(&chunk[..n])?;
+                left -= n;
+            }
+            Ok(())
+        }
+        ["private"] => {
+            let digest = stream.ssl().peer_certificate().map(|c| c.digest(MessageDigest::sha256()));
+            match digest {
+                Some(Ok(d)) => {
+                    write_header(stream, "20", "text/plain")?;
+                    writeln!(stream, "{}", certificates::fingerprint(&d))
+                }
+                _ => write_header(stream, "60", "Client certificate required")
+            }
+        }
+        _ => serve_file(stream, url.path(), root)
+    }
+}
+
+fn serve_file(stream: &mut SslStream<TcpStream>, url_path: &str, root: &Path) -> io::Result<()> {
+    let relative = PathBuf::from(percent_decode_str(url_path.trim_start_matches('/')).decode_utf8_lossy().to_string());
+    if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return write_header(stream, "51", "Not found");
+    }
+
+    let mut path = root.join(relative);
+    if path.is_dir() {
+        path = path.join("index.gmi");
+    }
+
+    let contents = match fs::read(&path) {
+        Ok(c) => c,
+        Err(_) => { return write_header(stream, "51", "Not found"); }
+    };
+
+    let mime = match path.extension().and_then(|e| e.to_str()) {
+        Some("gmi") | Some("gemini") => "text/gemini",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream"
+    };
+    write_header(stream, "20", mime)?;
+    stream.write_all(&contents)
+}
+
+fn handle(acceptor: &SslAcceptor, stream: TcpStream, root: &Path) {
+    let mut stream = match acceptor.accept(stream) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Handshake failed: {}", e);
+            return;
+        }
+    };
+
+    match read_request(&mut stream) {
+        Ok(request) => {
+            if let Err(e) = respond(&mut stream, &request, root) {
+                eprintln!("Could not respond to {}: {}", request, e);
+            }
+        }
+        Err(e) => { eprintln!("Bad request: {}", e); }
+    }
+    let _ = stream.shutdown();
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(Some(o)) => o,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let shared = match generated_acceptor() {
+        Ok(a) => Arc::new(a),
+        Err(e) => {
+            eprintln!("Could not generate a certificate: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let listener = match TcpListener::bind(("127.0.0.1", options.port)) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Could not listen on port {}: {}", options.port, e);
+            std::process::exit(1);
+        }
+    };
+    match listener.local_addr() {
+        Ok(address) => { println!("Listening on {}", address); }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let _ = io::stdout().flush();
+
+    let root = Arc::new(options.root);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => { continue; }
+        };
+
+        let acceptor = if options.rotate_certs {
+            match generated_acceptor() {
+                Ok(a) => Arc::new(a),
+                Err(e) => {
+                    eprintln!("Could not generate a certificate: {}", e);
+                    continue;
+                }
+            }
+        } else {
+            shared.clone()
+        };
+        let root = root.clone();
+        thread::spawn(move || handle(&acceptor, stream, &root));
+    }
+}
@@ -0,0 +1,198 @@
+use rusqlite;
+
+use crate::certificates;
+use crate::redirects;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bookmark {
+    pub id: i64,
+    pub url: String,
+    pub title: String,
+    pub tags: Vec<String>,
+    /// Unix timestamp of when the bookmark was added
+    pub added: i64
+}
+
+fn open_db() -> rusqlite::Result<rusqlite::Connection> {
+    let c = certificates::open_db()?;
+    create_tables(&c)?;
+    Ok(c)
+}
+
+fn create_tables(c: &rusqlite::Connection) -> rusqlite::Result<()> {
+    c.execute("CREATE TABLE IF NOT EXISTS bookmark (id INTEGER PRIMARY KEY, url TEXT UNIQUE NOT NULL, title TEXT NOT NULL, added INTEGER);", rusqlite::NO_PARAMS)?;
+    c.execute("CREATE TABLE IF NOT EXISTS bookmark_tag (bookmark INTEGER NOT NULL, tag TEXT NOT NULL, PRIMARY KEY (bookmark, tag));", rusqlite::NO_PARAMS)?;
+    // Bookmarks are moved along with permanent redirects
+    redirects::create_table(c)?;
+    Ok(())
+}
+
+/// Bookmarks the URL, or renames the existing bookmark for it. Returns the id of the bookmark.
+pub fn add(url: &str, title: &str) -> Result<i64, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    add_in(&conn, url, title)
+}
+
+fn add_in(conn: &rusqlite::Connection, url: &str, title: &str) -> Result<i64, String> {
+    let existing = conn.query_row("SELECT id FROM bookmark WHERE url=(?)", &[&url], |r| r.get(0));
+    match existing {
+        Ok(id) => {
+            rename_in(conn, id, title)?;
+            Ok(id)
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            conn.execute("INSERT INTO bookmark (url, title, added) VALUES (?, ?, ?)",
+                         rusqlite::params![url, title, certificates::now()])
+                .map_err(|e| e.to_string())?;
+            Ok(conn.last_insert_rowid())
+        }
+        Err(e) => Err(e.to_string())
+    }
+}
+
+/// Removes the bookmark, returning whether there was one with the id
+pub fn delete(id: i64) -> Result<bool, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    delete_in(&conn, id)
+}
+
+fn delete_in(conn: &rusqlite::Connection, id: i64) -> Result<bool, String> {
+    conn.execute("DELETE FROM bookmark_tag WHERE bookmark=(?)", &[&id]).map_err(|e| e.to_string())?;
+    let deleted = conn.execute("DELETE FROM bookmark WHERE id=(?)", &[&id]).map_err(|e| e.to_string())?;
+    Ok(deleted > 0)
+}
+
+pub fn rename(id: i64, title: &str) -> Result<bool, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    rename_in(&conn, id, title)
+}
+
+fn rename_in(conn: &rusqlite::Connection, id: i64, title: &str) -> Result<bool, String> {
+    let updated = conn.execute("UPDATE bookmark SET title=(?) WHERE id=(?)", rusqlite::params![title, id])
+        .map_err(|e| e.to_string())?;
+    Ok(updated > 0)
+}
+
+/// Replaces the tags of the bookmark, an empty list removing them all
+pub fn tag(id: i64, tags: &[String]) -> Result<bool, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    tag_in(&conn, id, tags)
+}
+
+fn tag_in(conn: &rusqlite::Connection, id: i64, tags: &[String]) -> Result<bool, String> {
+    let exists = conn.query_row("SELECT id FROM bookmark WHERE id=(?)", &[&id], |r| r.get::<_, i64>(0));
+    match exists {
+        Ok(_) => {}
+        Err(rusqlite::Error::QueryReturnedNoRows) => { return Ok(false); }
+        Err(e) => { return Err(e.to_string()); }
+    }
+
+    conn.execute("DELETE FROM bookmark_tag WHERE bookmark=(?)", &[&id]).map_err(|e| e.to_string())?;
+    for tag in tags {
+        conn.execute("INSERT OR IGNORE INTO bookmark_tag (bookmark, tag) VALUES (?, ?)", rusqlite::params![id, tag.to_lowercase()])
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(true)
+}
+
+/// All bookmarks by title, pointing at where their pages have permanently moved to
+pub fn list() -> Result<Vec<Bookmark>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    list_in(&conn)
+}
+
+fn list_in(conn: &rusqlite::Connection) -> Result<Vec<Bookmark>, String> {
+    follow_redirects(conn)?;
+
+    let mut stmt = conn.prepare("SELECT id, url, title, added FROM bookmark ORDER BY title COLLATE NOCASE, url")
+        .map_err(|e| e.to_string())?;
+    let rows = stmt.query_map(rusqlite::NO_PARAMS, |r| Ok(Bookmark {
+            id: r.get(0)?,
+            url: r.get(1)?,
+            title: r.get(2)?,
+            tags: Vec::new(),
+            added: r.get::<_, Option<i64>>(3)?.unwrap_or(0)
+        }))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Bookmark>>>())
+        .map_err(|e| e.to_string())?;
+
+    let mut tag_stmt = conn.prepare("SELECT tag FROM bookmark_tag WHERE bookmark=(?) ORDER BY tag").map_err(|e| e.to_string())?;
+    let mut bookmarks = Vec::new();
+    for mut bookmark in rows {
+        bookmark.tags = tag_stmt.query_map(&[&bookmark.id], |r| r.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>())
+            .map_err(|e| e.to_string())?;
+        bookmarks.push(bookmark);
+    }
+
+    Ok(bookmarks)
+}
+
+/// Rewrites bookmarks of pages that have permanently moved. A bookmark is left alone
+/// if the new location has been bookmarked separately.
+fn follow_redirects(conn: &rusqlite::Connection) -> Result<(), String> {
+    let mut stmt = conn.prepare("SELECT id, url FROM bookmark").map_err(|e| e.to_string())?;
+    let bookmarks = stmt.query_map(rusqlite::NO_PARAMS, |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<(i64, String)>>>())
+        .map_err(|e| e.to_string())?;
+
+    for (id, url) in bookmarks {
+        if let Some(target) = redirects::lookup_in(conn, &url)? {
+            conn.execute("UPDATE OR IGNORE bookmark SET url=(?) WHERE id=(?)", rusqlite::params![target, id])
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn bookmark_editing() {
+        let conn = test_db();
+
+        let first = add_in(&conn, "gemini://example.com/", "Example").unwrap();
+        let second = add_in(&conn, "gemini://example.org/", "another").unwrap();
+        assert_eq!(add_in(&conn, "gemini://example.com/", "Renamed").unwrap(), first);
+
+        assert!(rename_in(&conn, second, "Another").unwrap());
+        assert!(tag_in(&conn, first, &["News".to_string(), "tech".to_string()]).unwrap());
+        assert!(!tag_in(&conn, 1000, &["x".to_string()]).unwrap());
+
+        let list = list_in(&conn).unwrap();
+        assert_eq!(list.iter().map(|b| b.title.as_str()).collect::<Vec<&str>>(), vec!["Another", "Renamed"]);
+        assert_eq!(list[1].tags, vec!["news".to_string(), "tech".to_string()]);
+        assert!(list[0].tags.is_empty());
+
+        assert!(tag_in(&conn, first, &[]).unwrap());
+        assert!(delete_in(&conn, second).unwrap());
+        assert!(!delete_in(&conn, second).unwrap());
+        let list = list_in(&conn).unwrap();
+        assert_eq!(list.len(), 1);
+        assert!(list[0].tags.is_empty());
+    }
+
+    #[test]
+    fn bookmarks_follow_redirects() {
+        let conn = test_db();
+        let id = add_in(&conn, "gemini://example.com/old", "Old").unwrap();
+        add_in(&conn, "gemini://example.com/taken", "Taken").unwrap();
+        add_in(&conn, "gemini://example.com/moved", "Moved").unwrap();
+
+        redirects::remember_in(&conn, "gemini://example.com/old", "gemini://example.com/new").unwrap();
+        redirects::remember_in(&conn, "gemini://example.com/moved", "gemini://example.com/taken").unwrap();
+
+        let list = list_in(&conn).unwrap();
+        let old = list.iter().find(|b| b.id == id).unwrap();
+        assert_eq!(old.url, "gemini://example.com/new");
+        assert!(list.iter().any(|b| b.url == "gemini://example.com/moved"));
+    }
+}
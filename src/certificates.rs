@@ -1,65 +1,452 @@
 extern crate openssl;
+use openssl::asn1::Asn1Time;
 use openssl::ssl::{SslStream};
+use openssl::x509::X509Ref;
 
+use std::fs;
+use std::io::Write;
 use std::net::TcpStream;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use rusqlite;
 
+use crate::config;
+
+/// Details of a server certificate shown to the user when deciding whether to trust it
+#[derive(Clone, Debug)]
+pub struct CertInfo {
+    pub digest: Vec<u8>,
+    pub subject: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub not_after_timestamp: Option<i64>
+}
+
+/// A certificate pin stored in the trust database
+#[derive(Clone, Debug, PartialEq)]
+pub struct PinnedCert {
+    pub host: String,
+    pub port: u16,
+    pub digest: Vec<u8>,
+    pub not_after: Option<i64>,
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>
+}
+
+/// What to do with a server certificate, given the pin stored for its host and port
+#[derive(Debug, PartialEq)]
+enum TrustDecision {
+    /// First visit, pin the certificate
+    Pin,
+    /// The certificate matches the pin
+    Trusted,
+    /// The pinned certificate has expired, so the new one replaces it silently
+    Replace,
+    /// The certificate differs from a pin that is still valid
+    Changed
+}
+
 pub enum ServerCertError {
     CertNotPresent,
-    CertChanged
+    CertChanged { known_digest: Vec<u8>, cert: Box<CertInfo> },
+    Database(String)
 }
 
-fn open_db() -> rusqlite::Result<rusqlite::Connection> {
-    let c = rusqlite::Connection::open("/tmp/ruostepurkki.db")?;
+pub fn open_db() -> rusqlite::Result<rusqlite::Connection> {
+    let c = rusqlite::Connection::open(config::get().database_path())?;
     c.execute("CREATE TABLE IF NOT EXISTS certificate (host TEXT PRIMARY KEY, digest BLOB);", rusqlite::NO_PARAMS)?;
+    migrate(&c)?;
     Ok(c)
 }
 
-fn insert_into_db(host: &str, digest: &[u8]) -> rusqlite::Result<()> {
+/// Brings the certificate table up to date, tracking the schema version in `user_version`
+fn migrate(c: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let version: i64 = c.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |r| r.get(0))?;
+
+    if version < 1 {
+        c.execute_batch("BEGIN;
+                         ALTER TABLE certificate ADD COLUMN first_seen INTEGER;
+                         ALTER TABLE certificate ADD COLUMN last_seen INTEGER;
+                         PRAGMA user_version = 1;
+                         COMMIT;")?;
+    }
+
+    if version < 2 {
+        // Pins used to be keyed by host only, existing ones are assumed to be for the default port
+        c.execute_batch("BEGIN;
+                         CREATE TABLE certificate_v2 (host TEXT NOT NULL, port INTEGER NOT NULL, digest BLOB,
+                                                      not_after INTEGER, first_seen INTEGER, last_seen INTEGER,
+                                                      PRIMARY KEY (host, port));
+                         INSERT INTO certificate_v2 (host, port, digest, first_seen, last_seen)
+                             SELECT host, 1965, digest, first_seen, last_seen FROM certificate;
+                         DROP TABLE certificate;
+                         ALTER TABLE certificate_v2 RENAME TO certificate;
+                         PRAGMA user_version = 2;
+                         COMMIT;")?;
+    }
+
+    Ok(())
+}
+
+/// Current time as a unix timestamp
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+fn store_pin(conn: &rusqlite::Connection, pin: &PinnedCert) -> rusqlite::Result<()> {
+    conn.execute("INSERT OR REPLACE INTO certificate (host, port, digest, not_after, first_seen, last_seen) VALUES (?, ?, ?, ?, ?, ?)",
+                 rusqlite::params![pin.host, pin.port, pin.digest, pin.not_after, pin.first_seen, pin.last_seen])?;
+    Ok(())
+}
+
+/// Pins a new certificate for the host and port, replacing any previously stored one
+pub fn replace_in_db(host: &str, port: u16, cert: &CertInfo) -> rusqlite::Result<()> {
     let conn = open_db()?;
-    conn.execute("INSERT INTO certificate (host, digest) VALUES (?, ?)", rusqlite::params![host, digest])?;
+    let now = now();
+    store_pin(&conn, &PinnedCert {
+        host: host.to_string(),
+        port,
+        digest: cert.digest.clone(),
+        not_after: cert.not_after_timestamp,
+        first_seen: Some(now),
+        last_seen: Some(now)
+    })
+}
+
+fn update_last_seen(host: &str, port: u16, not_after: Option<i64>) -> rusqlite::Result<()> {
+    let conn = open_db()?;
+    conn.execute("UPDATE certificate SET last_seen=(?), not_after=COALESCE(?, not_after) WHERE host=(?) AND port=(?)",
+                 rusqlite::params![now(), not_after, host, port])?;
     Ok(())
 }
 
-fn cert_in_db(host: &str) -> rusqlite::Result<Option<Vec<u8>>> {
+fn pin_from_row(r: &rusqlite::Row) -> rusqlite::Result<PinnedCert> {
+    Ok(PinnedCert {
+        host: r.get(0)?,
+        port: r.get(1)?,
+        digest: r.get(2)?,
+        not_after: r.get(3)?,
+        first_seen: r.get(4)?,
+        last_seen: r.get(5)?
+    })
+}
+
+pub fn list_pins() -> rusqlite::Result<Vec<PinnedCert>> {
     let conn = open_db()?;
+    let mut stmt = conn.prepare("SELECT host, port, digest, not_after, first_seen, last_seen FROM certificate ORDER BY host, port")?;
+    let pins = stmt.query_map(rusqlite::NO_PARAMS, pin_from_row)?.collect();
+    pins
+}
+
+pub fn get_pin(host: &str, port: u16) -> rusqlite::Result<Option<PinnedCert>> {
+    let conn = open_db()?;
+    let stmt = "SELECT host, port, digest, not_after, first_seen, last_seen FROM certificate WHERE host=(?) AND port=(?)";
+    match conn.query_row(stmt, rusqlite::params![host, port], pin_from_row) {
+        Ok(pin) => Ok(Some(pin)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+/// Removes the pin for the host and port, returning whether there was one
+pub fn forget(host: &str, port: u16) -> rusqlite::Result<bool> {
+    let conn = open_db()?;
+    let deleted = conn.execute("DELETE FROM certificate WHERE host=(?) AND port=(?)", rusqlite::params![host, port])?;
+    Ok(deleted > 0)
+}
+
+/// Splits "host:port" into its parts, defaulting to the Gemini port when none is given
+pub fn parse_host_port(s: &str) -> (String, u16) {
+    if let Some(i) = s.rfind(':') {
+        if let Ok(port) = s[i+1..].parse::<u16>() {
+            return (s[..i].to_string(), port);
+        }
+    }
+
+    (s.to_string(), 1965)
+}
+
+/// Writes every pin to a text file, one `host:port fingerprint first_seen last_seen not_after` line each
+pub fn export_pins(path: &Path) -> Result<usize, String> {
+    let pins = list_pins().map_err(|e| e.to_string())?;
+
+    let mut file = fs::File::create(path).map_err(|e| e.to_string())?;
+    let mut contents = "# ruostepurkki known hosts\n# host:port sha256-fingerprint first-seen last-seen not-after\n".to_string();
+    for pin in &pins {
+        contents.push_str(&format!("{}:{} {} {} {} {}\n",
+            pin.host,
+            pin.port,
+            fingerprint(&pin.digest),
+            pin.first_seen.unwrap_or(0),
+            pin.last_seen.unwrap_or(0),
+            pin.not_after.unwrap_or(0)));
+    }
+    file.write_all(contents.as_bytes()).map_err(|e| e.to_string())?;
+
+    Ok(pins.len())
+}
+
+/// Reads pins written by `export_pins`, replacing any existing pins for the same hosts
+pub fn import_pins(path: &Path) -> Result<usize, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let mut pins = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        match parse_pin_line(line) {
+            Ok(Some(pin)) => pins.push(pin),
+            Ok(None) => {},
+            Err(e) => { return Err(format!("Line {}: {}", i + 1, e)); }
+        }
+    }
+
+    let mut conn = open_db().map_err(|e| e.to_string())?;
+    let tx = conn.transaction().map_err(|e| e.to_string())?;
+    for pin in &pins {
+        store_pin(&tx, pin).map_err(|e| e.to_string())?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    Ok(pins.len())
+}
+
+fn parse_pin_line(line: &str) -> Result<Option<PinnedCert>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
 
-    let stmt = "SELECT digest FROM certificate WHERE host=(?)";
-    let result = conn.query_row(stmt, &[&host], |r| r.get(0))?;
-    Ok(Some(result))
+    let fields = line.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() < 2 {
+        return Err("Expected a host and a fingerprint".to_string());
+    }
+
+    let digest = match parse_fingerprint(fields[1]) {
+        Some(d) => d,
+        None => { return Err(format!("Invalid fingerprint '{}'", fields[1])); }
+    };
+
+    let timestamp = |i: usize| -> Result<Option<i64>, String> {
+        match fields.get(i) {
+            Some(t) => match t.parse::<i64>() {
+                Ok(0) => Ok(None),
+                Ok(t) => Ok(Some(t)),
+                Err(_) => Err(format!("Invalid timestamp '{}'", t))
+            },
+            None => Ok(None)
+        }
+    };
+
+    let (host, port) = parse_host_port(fields[0]);
+
+    Ok(Some(PinnedCert {
+        host,
+        port,
+        digest,
+        not_after: timestamp(4)?,
+        first_seen: timestamp(2)?,
+        last_seen: timestamp(3)?
+    }))
+}
+
+fn parse_fingerprint(s: &str) -> Option<Vec<u8>> {
+    let digest = s.split(':')
+        .map(|b| if b.len() == 2 { u8::from_str_radix(b, 16).ok() } else { None })
+        .collect::<Option<Vec<u8>>>()?;
+
+    if digest.len() == 32 {
+        Some(digest)
+    } else {
+        None
+    }
+}
+
+/// Formats a unix timestamp as a UTC date and time
+pub fn format_time(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Formats a digest as colon separated hex, e.g. "AB:CD:EF"
+pub fn fingerprint(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(":")
+}
+
+fn cert_info(cert: &X509Ref, digest: Vec<u8>) -> CertInfo {
+    let subject = cert.subject_name().entries().map(|e| {
+        let key = e.object().nid().short_name().unwrap_or("?");
+        let value = match e.data().as_utf8() {
+            Ok(v) => v.to_string(),
+            Err(_) => "?".to_string()
+        };
+        format!("{}={}", key, value)
+    }).collect::<Vec<String>>().join(", ");
+
+    CertInfo {
+        digest,
+        subject,
+        not_before: cert.not_before().to_string(),
+        not_after: cert.not_after().to_string(),
+        not_after_timestamp: not_after_timestamp(cert)
+    }
+}
+
+fn not_after_timestamp(cert: &X509Ref) -> Option<i64> {
+    let epoch = Asn1Time::from_unix(0).ok()?;
+    let diff = epoch.diff(cert.not_after()).ok()?;
+    Some(diff.days as i64 * 86400 + diff.secs as i64)
+}
+
+fn decide(pinned: Option<&PinnedCert>, digest: &[u8], now: i64) -> TrustDecision {
+    match pinned {
+        None => TrustDecision::Pin,
+        Some(pin) if pin.digest == digest => TrustDecision::Trusted,
+        Some(PinnedCert { not_after: Some(expiry), .. }) if *expiry < now => TrustDecision::Replace,
+        Some(_) => TrustDecision::Changed
+    }
 }
 
-pub fn check_cert(stream: &SslStream<TcpStream>, host: &str) -> Result<(), (ServerCertError, Option<String>)> {
+/// Checks the server certificate against the one pinned for the host and port. A changed
+/// certificate is accepted silently only if the pinned one has already expired, or if its
+/// digest matches `accepted`.
+pub fn check_cert(stream: &SslStream<TcpStream>, host: &str, port: u16, accepted: Option<&[u8]>) -> Result<(), ServerCertError> {
     let cert = match stream.ssl().peer_certificate() {
         Some(c) => c,
-        None => { 
-            return Err( (ServerCertError::CertNotPresent, None) );
+        None => {
+            return Err(ServerCertError::CertNotPresent);
         }
     };
-    let digest: Vec<u8> = cert.digest(openssl::hash::MessageDigest::sha256()).unwrap().as_ref().iter().cloned().collect();
-    
-    let known_digest: Vec<u8> = match cert_in_db(host) {
-        Ok(Some(v)) => v,
-        Ok(None) => {
-            return Err((ServerCertError::CertChanged, Some("Something weird must have happened".to_string())));
-        },
+    let digest: Vec<u8> = cert.digest(openssl::hash::MessageDigest::sha256()).unwrap().to_vec();
+    let info = cert_info(&cert, digest);
+
+    let pinned = match get_pin(host, port) {
+        Ok(p) => p,
         Err(err) => {
-            if err == rusqlite::Error::QueryReturnedNoRows {
-                insert_into_db(host, &digest).unwrap();
+            return Err(ServerCertError::Database(err.to_string()));
+        }
+    };
+
+    let result = match decide(pinned.as_ref(), &info.digest, now()) {
+        TrustDecision::Pin | TrustDecision::Replace => replace_in_db(host, port, &info),
+        TrustDecision::Trusted => update_last_seen(host, port, info.not_after_timestamp),
+        TrustDecision::Changed => {
+            if accepted == Some(&info.digest[..]) {
                 return Ok(());
             }
-            else {
-                println!("Error: {}", err);
-                return Err((ServerCertError::CertChanged, Some(err.to_string())));
-            }
+            return Err(ServerCertError::CertChanged {
+                known_digest: pinned.map(|p| p.digest).unwrap_or_default(),
+                cert: Box::new(info)
+            });
         }
     };
 
-    if digest == known_digest {
-        return Ok(());
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => Err(ServerCertError::Database(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_format() {
+        assert_eq!(fingerprint(&[0xab, 0x01, 0xff]), "AB:01:FF");
+        assert_eq!(fingerprint(&[]), "");
+    }
+
+    #[test]
+    fn pin_lines() {
+        let digest: Vec<u8> = (0..32).collect();
+        let line = format!("example.com:1966 {} 1600000000 1600000100 1700000000", fingerprint(&digest));
+        assert_eq!(parse_pin_line(&line).unwrap(), Some(PinnedCert {
+            host: "example.com".to_string(),
+            port: 1966,
+            digest: digest.clone(),
+            not_after: Some(1700000000),
+            first_seen: Some(1600000000),
+            last_seen: Some(1600000100)
+        }));
+
+        let line = format!("  example.org   {}", fingerprint(&digest));
+        let pin = parse_pin_line(&line).unwrap().unwrap();
+        assert_eq!(pin.host, "example.org");
+        assert_eq!(pin.port, 1965);
+        assert_eq!(pin.first_seen, None);
+        assert_eq!(pin.not_after, None);
+
+        assert_eq!(parse_pin_line("# comment").unwrap(), None);
+        assert_eq!(parse_pin_line("").unwrap(), None);
+        assert!(parse_pin_line("example.com").is_err());
+        assert!(parse_pin_line("example.com AB:CD").is_err());
+        assert!(parse_pin_line(&format!("example.com {} yesterday", fingerprint(&digest))).is_err());
     }
-    else {
-        return Err((ServerCertError::CertChanged, None));
+
+    #[test]
+    fn host_and_port() {
+        assert_eq!(parse_host_port("example.com"), ("example.com".to_string(), 1965));
+        assert_eq!(parse_host_port("example.com:1966"), ("example.com".to_string(), 1966));
+        assert_eq!(parse_host_port("[::1]:1966"), ("[::1]".to_string(), 1966));
+        assert_eq!(parse_host_port("[::1]"), ("[::1]".to_string(), 1965));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tofu_policy() {
+        let pin = PinnedCert {
+            host: "example.com".to_string(),
+            port: 1965,
+            digest: vec![1, 2, 3],
+            not_after: Some(1000),
+            first_seen: None,
+            last_seen: None
+        };
+
+        assert_eq!(decide(None, &[1, 2, 3], 500), TrustDecision::Pin);
+        assert_eq!(decide(Some(&pin), &[1, 2, 3], 500), TrustDecision::Trusted);
+        assert_eq!(decide(Some(&pin), &[1, 2, 3], 2000), TrustDecision::Trusted);
+        assert_eq!(decide(Some(&pin), &[4, 5, 6], 500), TrustDecision::Changed);
+        assert_eq!(decide(Some(&pin), &[4, 5, 6], 2000), TrustDecision::Replace);
+
+        let unknown_expiry = PinnedCert { not_after: None, ..pin };
+        assert_eq!(decide(Some(&unknown_expiry), &[4, 5, 6], 2000), TrustDecision::Changed);
+    }
+
+    #[test]
+    fn migrate_host_only_pins() {
+        let c = rusqlite::Connection::open_in_memory().unwrap();
+        c.execute("CREATE TABLE certificate (host TEXT PRIMARY KEY, digest BLOB);", rusqlite::NO_PARAMS).unwrap();
+        c.execute("INSERT INTO certificate (host, digest) VALUES ('example.com', x'0102')", rusqlite::NO_PARAMS).unwrap();
+
+        migrate(&c).unwrap();
+
+        let pin = c.query_row("SELECT host, port, digest, not_after, first_seen, last_seen FROM certificate", rusqlite::NO_PARAMS, pin_from_row).unwrap();
+        assert_eq!(pin.host, "example.com");
+        assert_eq!(pin.port, 1965);
+        assert_eq!(pin.digest, vec![1, 2]);
+        assert_eq!(pin.not_after, None);
+
+        let version: i64 = c.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |r| r.get(0)).unwrap();
+        assert_eq!(version, 2);
+    }
+
+    #[test]
+    fn time_format() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_time(1602936245), "2020-10-17 12:04:05 UTC");
+    }
+}
@@ -0,0 +1,352 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::handlers;
+use crate::protocol;
+
+const APP_NAME: &str = "ruostepurkki";
+const DATABASE_FILE: &str = "ruostepurkki.db";
+
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Where the database lived before it was moved under the data directory
+const LEGACY_DATABASE: &str = "/tmp/ruostepurkki.db";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub total_timeout: Option<Duration>,
+    pub max_body_size: Option<usize>,
+    /// Where downloaded files are saved
+    pub download_dir: PathBuf,
+    /// Largest download accepted, in bytes
+    pub max_download_size: Option<usize>,
+    /// Memory used for pages kept for going back and forwards, in bytes
+    pub cache_size: Option<usize>,
+    /// Programs for opening documents that are not text, tried in order
+    pub handlers: Vec<handlers::Handler>
+}
+
+/// Locations given on the command line, taking precedence over everything else
+#[derive(Default)]
+pub struct Overrides {
+    pub data_dir: Option<PathBuf>,
+    pub config_file: Option<PathBuf>
+}
+
+impl Config {
+    pub fn new(data_dir: PathBuf) -> Self {
+        Config {
+            data_dir,
+            connect_timeout: Some(protocol::DEFAULT_CONNECT_TIMEOUT),
+            read_timeout: Some(protocol::DEFAULT_READ_TIMEOUT),
+            total_timeout: Some(protocol::DEFAULT_TOTAL_TIMEOUT),
+            max_body_size: Some(protocol::DEFAULT_MAX_BODY_SIZE),
+            download_dir: default_download_dir(),
+            max_download_size: None,
+            cache_size: Some(DEFAULT_CACHE_SIZE),
+            handlers: Vec::new()
+        }
+    }
+
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.join(DATABASE_FILE)
+    }
+
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "data_dir" => { self.data_dir = PathBuf::from(value); }
+            "connect_timeout" => { self.connect_timeout = parse_timeout(value)?; }
+            "read_timeout" => { self.read_timeout = parse_timeout(value)?; }
+            "total_timeout" => { self.total_timeout = parse_timeout(value)?; }
+            "max_body_size" => { self.max_body_size = parse_size(value)?; }
+            "download_dir" => { self.download_dir = PathBuf::from(value); }
+            "max_download_size" => { self.max_download_size = parse_size(value)?; }
+            "cache_size" => { self.cache_size = parse_size(value)?; }
+            "handler" => { self.handlers.push(handlers::parse_handler(value)?); }
+            _ => { return Err(format!("Unknown setting '{}'", key)); }
+        }
+
+        Ok(())
+    }
+}
+
+/// Makes the configuration available through `get`. Can only be done once.
+pub fn init(config: Config) -> Result<(), String> {
+    CONFIG.set(config).map_err(|_| "Configuration already initialised".to_string())
+}
+
+/// The active configuration, or the defaults if `init` was never called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| Config::new(default_data_dir()))
+}
+
+/// Resolves the configuration from command line overrides, environment variables,
+/// the configuration file and finally the XDG base directories
+pub fn load(overrides: Overrides) -> Result<Config, String> {
+    let explicit_config = overrides.config_file.clone()
+        .or_else(|| env_path("RUOSTEPURKKI_CONFIG"));
+    let config_file = explicit_config.clone().unwrap_or_else(default_config_file);
+
+    let mut config = Config::new(default_data_dir());
+
+    match fs::read_to_string(&config_file) {
+        Ok(contents) => {
+            parse_config(&mut config, &contents).map_err(|e| format!("{}: {}", config_file.display(), e))?;
+        }
+        Err(e) => {
+            if explicit_config.is_some() {
+                return Err(format!("Could not read {}: {}", config_file.display(), e));
+            }
+        }
+    }
+
+    if let Some(dir) = overrides.data_dir.or_else(|| env_path("RUOSTEPURKKI_DATA_DIR")) {
+        config.data_dir = dir;
+    }
+
+    Ok(config)
+}
+
+fn parse_config(config: &mut Config, contents: &str) -> Result<(), String> {
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), line[pos+1..].trim()),
+            None => { return Err(format!("line {}: expected 'key = value'", i + 1)); }
+        };
+
+        config.apply(key, value).map_err(|e| format!("line {}: {}", i + 1, e))?;
+    }
+
+    Ok(())
+}
+
+/// Timeouts are given in seconds, 0 meaning no limit
+fn parse_timeout(value: &str) -> Result<Option<Duration>, String> {
+    match value.parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+        Err(_) => Err(format!("'{}' is not a number of seconds", value))
+    }
+}
+
+/// Sizes are given in bytes, 0 meaning no limit
+fn parse_size(value: &str) -> Result<Option<usize>, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Ok(None),
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(_) => Err(format!("'{}' is not a number of bytes", value))
+    }
+}
+
+fn env_path(var: &str) -> Option<PathBuf> {
+    match env::var_os(var) {
+        Some(v) if !v.is_empty() => Some(PathBuf::from(v)),
+        _ => None
+    }
+}
+
+/// Looks up an XDG base directory, ignoring relative paths as the specification requires
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match env_path(var) {
+        Some(p) if p.is_absolute() => p,
+        _ => {
+            let home = env_path("HOME").unwrap_or_else(|| PathBuf::from("."));
+            home.join(fallback)
+        }
+    }
+}
+
+fn default_data_dir() -> PathBuf {
+    xdg_dir("XDG_DATA_HOME", ".local/share").join(APP_NAME)
+}
+
+/// The download directory set in the user's user-dirs.dirs, otherwise ~/Downloads
+fn default_download_dir() -> PathBuf {
+    let home = env_path("HOME").unwrap_or_else(|| PathBuf::from("."));
+    let user_dirs = xdg_dir("XDG_CONFIG_HOME", ".config").join("user-dirs.dirs");
+    fs::read_to_string(user_dirs).ok()
+        .and_then(|contents| parse_user_dir(&contents, "XDG_DOWNLOAD_DIR", &home))
+        .unwrap_or_else(|| home.join("Downloads"))
+}
+
+/// Finds a directory in user-dirs.dirs, where it is either `"$HOME/path"` or `"/path"`
+fn parse_user_dir(contents: &str, key: &str, home: &Path) -> Option<PathBuf> {
+    let value = contents.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim().trim_matches('"'))?;
+
+    match value.strip_prefix("$HOME") {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(home.join(rest.trim_start_matches('/'))),
+        Some(_) => None,
+        None if value.starts_with('/') => Some(PathBuf::from(value)),
+        None => None
+    }
+}
+
+fn default_config_file() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config").join(APP_NAME).join("config")
+}
+
+/// Creates the data directory readable only by the user and moves an old database from /tmp into it
+pub fn prepare_data_dir(config: &Config) -> Result<(), String> {
+    create_private_dir(&config.data_dir)
+        .map_err(|e| format!("Could not create {}: {}", config.data_dir.display(), e))?;
+
+    let database = config.database_path();
+    if !database.exists() {
+        migrate_legacy_database(Path::new(LEGACY_DATABASE), &database, &config.data_dir)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+fn migrate_legacy_database(legacy: &Path, database: &Path, data_dir: &Path) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(legacy) {
+        Ok(m) => m,
+        Err(_) => { return Ok(()); }
+    };
+
+    // /tmp is shared, so only take over a regular file that belongs to us
+    if !metadata.file_type().is_file() || !owned_like(&metadata, data_dir) {
+        return Ok(());
+    }
+
+    fs::copy(legacy, database).map_err(|e| format!("Could not migrate {}: {}", legacy.display(), e))?;
+    restrict_permissions(database).map_err(|e| format!("Could not set permissions on {}: {}", database.display(), e))?;
+    let _ = fs::remove_file(legacy);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn owned_like(metadata: &fs::Metadata, reference: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match fs::metadata(reference) {
+        Ok(r) => r.uid() == metadata.uid(),
+        Err(_) => false
+    }
+}
+
+#[cfg(not(unix))]
+fn owned_like(_metadata: &fs::Metadata, _reference: &Path) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config::new(PathBuf::from("/data"))
+    }
+
+    #[test]
+    fn config_file_parsing() {
+        let mut config = test_config();
+        parse_config(&mut config, "# comment\n\n  data_dir = /home/user/pins  \n").unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/home/user/pins"));
+        assert_eq!(config.database_path(), PathBuf::from("/home/user/pins/ruostepurkki.db"));
+
+        let mut config = test_config();
+        parse_config(&mut config, "connect_timeout = 5\nread_timeout=0").unwrap();
+        assert_eq!(config.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.read_timeout, None);
+        assert_eq!(config.total_timeout, Some(protocol::DEFAULT_TOTAL_TIMEOUT));
+        assert!(parse_config(&mut config, "total_timeout = soon").is_err());
+
+        let mut config = test_config();
+        parse_config(&mut config, "max_body_size = 1048576").unwrap();
+        assert_eq!(config.max_body_size, Some(1048576));
+        parse_config(&mut config, "max_body_size = 0").unwrap();
+        assert_eq!(config.max_body_size, None);
+        assert!(parse_config(&mut config, "max_body_size = -1").is_err());
+
+        let mut config = test_config();
+        assert_eq!(config.max_download_size, None);
+        parse_config(&mut config, "download_dir = /home/user/dl\nmax_download_size = 1000").unwrap();
+        assert_eq!(config.download_dir, PathBuf::from("/home/user/dl"));
+        assert_eq!(config.max_download_size, Some(1000));
+
+        let mut config = test_config();
+        assert_eq!(config.cache_size, Some(DEFAULT_CACHE_SIZE));
+        parse_config(&mut config, "cache_size = 0").unwrap();
+        assert_eq!(config.cache_size, None);
+
+        let mut config = test_config();
+        parse_config(&mut config, "handler = image/*; feh %s\nhandler = audio/*; mpv %s; needsterminal").unwrap();
+        assert_eq!(config.handlers.len(), 2);
+        assert!(config.handlers[1].needs_terminal);
+        assert!(parse_config(&mut config, "handler = feh").is_err());
+
+        let mut config = test_config();
+        assert!(parse_config(&mut config, "data_dir /nowhere").is_err());
+        assert!(parse_config(&mut config, "colour = red").is_err());
+    }
+
+    #[test]
+    fn user_dirs_parsing() {
+        let home = Path::new("/home/user");
+        let contents = "# written by xdg-user-dirs-update\nXDG_DESKTOP_DIR=\"$HOME/Desktop\"\nXDG_DOWNLOAD_DIR=\"$HOME/Lataukset\"\n";
+        assert_eq!(parse_user_dir(contents, "XDG_DOWNLOAD_DIR", home), Some(PathBuf::from("/home/user/Lataukset")));
+        assert_eq!(parse_user_dir("XDG_DOWNLOAD_DIR=\"/srv/dl\"", "XDG_DOWNLOAD_DIR", home), Some(PathBuf::from("/srv/dl")));
+        assert_eq!(parse_user_dir("XDG_DOWNLOAD_DIR=\"$HOMEWORK\"", "XDG_DOWNLOAD_DIR", home), None);
+        assert_eq!(parse_user_dir("#XDG_DOWNLOAD_DIR=\"/srv/dl\"", "XDG_DOWNLOAD_DIR", home), None);
+        assert_eq!(parse_user_dir(contents, "XDG_MUSIC_DIR", home), None);
+    }
+
+    #[test]
+    fn legacy_database_migration() {
+        let dir = env::temp_dir().join(format!("ruostepurkki-test-{}", std::process::id()));
+        let data_dir = dir.join("data");
+        create_private_dir(&data_dir).unwrap();
+
+        let legacy = dir.join("legacy.db");
+        let database = data_dir.join(DATABASE_FILE);
+        fs::write(&legacy, b"pins").unwrap();
+
+        migrate_legacy_database(&legacy, &database, &data_dir).unwrap();
+        assert_eq!(fs::read(&database).unwrap(), b"pins");
+        assert!(!legacy.exists());
+
+        // Nothing to migrate the second time around
+        migrate_legacy_database(&legacy, &database, &data_dir).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
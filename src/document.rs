@@ -3,6 +3,9 @@ extern crate mime;
 extern crate regex;
 use regex::Regex;
 
+extern crate encoding_rs;
+use encoding_rs::{Encoding, UTF_8};
+
 #[derive(Clone, PartialEq)]
 pub enum Line {
     Text(String),
@@ -97,24 +100,52 @@ pub fn parse_gemini_doc(page: &str) -> Vec<Line> {
     lines
 }
 
+/// A success response without a MIME type is to be treated as this
+const DEFAULT_MIME: &str = "text/gemini; charset=utf-8";
+
+fn parse_mime(mime: &str) -> Option<mime::Mime> {
+    let mime = match mime.trim() {
+        "" => DEFAULT_MIME,
+        m => m
+    };
+    mime.parse().ok()
+}
+
 pub fn is_gemini_doc(mime: &str) -> bool {
-    let m: mime::Mime = match mime.parse() {
-        Ok(m) => m,
-        Err(_) => {return false;}
+    let m = match parse_mime(mime) {
+        Some(m) => m,
+        None => {return false;}
     };
 
     m.type_() == "text" && m.subtype() == "gemini"
 }
 
 pub fn is_text_doc(mime: &str) -> bool {
-    let m: mime::Mime = match mime.parse() {
-        Ok(m) => m,
-        Err(_) => {return false;}
+    let m = match parse_mime(mime) {
+        Some(m) => m,
+        None => {return false;}
     };
 
     m.type_() == "text"
 }
 
+/// Value of a parameter of the MIME type, such as `charset` or `lang`
+pub fn mime_param(mime: &str, name: &str) -> Option<String> {
+    let m = parse_mime(mime)?;
+    let value = m.get_param(name)?;
+    Some(value.as_str().to_string())
+}
+
+/// Decodes text in the given charset, or UTF-8 when there is none or it is not known.
+/// Bytes that are not valid in the charset become replacement characters.
+pub fn decode_text(raw: &[u8], charset: Option<&str>) -> String {
+    let encoding = charset
+        .and_then(|c| Encoding::for_label(c.trim().as_bytes()))
+        .unwrap_or(UTF_8);
+    let (text, _, _) = encoding.decode(raw);
+    text.into_owned()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -294,6 +325,29 @@ mod tests {
 
         assert_eq!(is_gemini_doc(geminimime), true);
         assert_eq!(is_gemini_doc(other), false);
+        assert!(is_gemini_doc("text/gemini; charset=utf-8; lang=fi"));
+        assert!(is_gemini_doc(""));
+        assert!(!is_text_doc("image/png"));
+    }
+
+    #[test]
+    fn mime_params() {
+        let mime = "text/gemini; charset=ISO-8859-1; lang=fi";
+        assert_eq!(mime_param(mime, "charset").as_deref(), Some("iso-8859-1"));
+        assert_eq!(mime_param(mime, "lang").as_deref(), Some("fi"));
+        assert_eq!(mime_param("text/plain;lang=\"en-GB\"", "lang").as_deref(), Some("en-GB"));
+        assert_eq!(mime_param("text/gemini", "charset"), None);
+        assert_eq!(mime_param("", "charset").as_deref(), Some("utf-8"));
+    }
+
+    #[test]
+    fn charset_decoding() {
+        assert_eq!(decode_text(b"Hyv\xe4\xe4 p\xe4iv\xe4\xe4", Some("iso-8859-1")), "Hyvää päivää");
+        assert_eq!(decode_text(b"\x93quoted\x94 \x80", Some("windows-1252")), "\u{201c}quoted\u{201d} \u{20ac}");
+        assert_eq!(decode_text(b"\x93\xfa\x96\x7b\x8c\xea", Some("Shift_JIS")), "日本語");
+        assert_eq!(decode_text(b"\xa4 euro", Some("ISO-8859-15")), "\u{20ac} euro");
+        assert_eq!(decode_text("錆".as_bytes(), None), "錆");
+        assert_eq!(decode_text(b"bad \xff byte", Some("no-such-charset")), "bad \u{fffd} byte");
     }
     
 }
\ No newline at end of file
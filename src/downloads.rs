@@ -0,0 +1,348 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::handlers;
+use crate::protocol;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    Running,
+    Finished,
+    Failed(String),
+    Cancelled
+}
+
+/// Messages from the thread writing a download
+enum Update {
+    Received(usize),
+    Ended(State)
+}
+
+/// What a download is of and where it goes
+pub struct Target {
+    pub url: String,
+    pub mime: String,
+    pub path: PathBuf,
+    /// Program to open the file with once it is complete
+    pub open_with: Option<handlers::Handler>
+}
+
+/// A response body being written to a file
+pub struct Download {
+    pub id: usize,
+    pub url: String,
+    pub mime: String,
+    pub path: PathBuf,
+    /// Program to open the file with once it is complete
+    pub open_with: Option<handlers::Handler>,
+    pub received: usize,
+    pub state: State,
+    started: Instant,
+    ended: Option<Instant>,
+    cancel: Arc<AtomicBool>,
+    updates: mpsc::Receiver<Update>
+}
+
+impl Download {
+    pub fn elapsed(&self) -> Duration {
+        self.ended.unwrap_or_else(Instant::now).duration_since(self.started)
+    }
+
+    /// Average transfer rate so far in bytes per second
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            self.received as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Keeps track of the downloads started during the session
+#[derive(Default)]
+pub struct Manager {
+    downloads: Vec<Download>,
+    next_id: usize
+}
+
+impl Manager {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts writing the body to `file` on its own thread. `cancel` is the flag of the
+    /// request the body came from, so cancelling the download also closes the connection.
+    pub fn start(&mut self, target: Target, file: fs::File, body: protocol::Body, cancel: Arc<AtomicBool>) -> usize {
+        self.next_id += 1;
+        let (tx, rx) = mpsc::channel();
+        let thread_path = target.path.clone();
+        let thread_cancel = cancel.clone();
+        thread::spawn(move || write_download(body, file, &thread_path, &thread_cancel, tx));
+
+        self.downloads.push(Download {
+            id: self.next_id,
+            url: target.url,
+            mime: target.mime,
+            path: target.path,
+            open_with: target.open_with,
+            received: 0,
+            state: State::Running,
+            started: Instant::now(),
+            ended: None,
+            cancel,
+            updates: rx
+        });
+        self.next_id
+    }
+
+    /// Takes in what the download threads have reported, returning the ids of downloads that ended
+    pub fn poll(&mut self) -> Vec<usize> {
+        let mut ended = Vec::new();
+
+        for download in self.downloads.iter_mut().filter(|d| d.state == State::Running) {
+            loop {
+                match download.updates.try_recv() {
+                    Ok(Update::Received(n)) => { download.received = n; }
+                    Ok(Update::Ended(state)) => {
+                        download.state = state;
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => { break; }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        download.state = State::Failed("Download thread stopped unexpectedly".to_string());
+                        break;
+                    }
+                }
+            }
+
+            if download.state != State::Running {
+                download.ended = Some(Instant::now());
+                ended.push(download.id);
+            }
+        }
+
+        ended
+    }
+
+    /// Asks a running download to stop, it ends once its thread notices
+    pub fn cancel(&mut self, id: usize) -> bool {
+        match self.get(id) {
+            Some(d) if d.state == State::Running => {
+                d.cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Cancels every running download and waits for their threads to remove the files,
+    /// removing the files of those that do not end in time
+    pub fn stop_all(&mut self, timeout: Duration) {
+        for download in self.running() {
+            download.cancel.store(true, Ordering::Relaxed);
+        }
+
+        let started = Instant::now();
+        while self.is_active() && started.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(10));
+            self.poll();
+        }
+
+        for download in self.running() {
+            let _ = fs::remove_file(&download.path);
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Download> {
+        self.downloads.iter().find(|d| d.id == id)
+    }
+
+    pub fn list(&self) -> &[Download] {
+        &self.downloads
+    }
+
+    pub fn running(&self) -> impl Iterator<Item = &Download> {
+        self.downloads.iter().filter(|d| d.state == State::Running)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.running().next().is_some()
+    }
+}
+
+/// Copies the body into the file, removing the file if the download does not complete
+fn write_download(mut body: protocol::Body, mut file: fs::File, path: &Path, cancel: &AtomicBool, tx: mpsc::Sender<Update>) {
+    let mut chunk = [0u8; 8192];
+    let mut received = 0;
+
+    let state = loop {
+        if cancel.load(Ordering::Relaxed) {
+            break State::Cancelled;
+        }
+        match body.read(&mut chunk) {
+            Ok(0) => {
+                break match file.flush() {
+                    Ok(()) => State::Finished,
+                    Err(e) => State::Failed(format!("Could not write {}: {}", path.display(), e))
+                };
+            }
+            Ok(n) => {
+                if let Err(e) = file.write_all(&chunk[..n]) {
+                    break State::Failed(format!("Could not write {}: {}", path.display(), e));
+                }
+                received += n;
+                let _ = tx.send(Update::Received(received));
+            }
+            Err(e) => {
+                break match protocol::Error::from(e) {
+                    protocol::Error::Cancelled => State::Cancelled,
+                    e => State::Failed(e.to_string())
+                };
+            }
+        }
+    };
+
+    if state != State::Finished {
+        drop(file);
+        let _ = fs::remove_file(path);
+    }
+    let _ = tx.send(Update::Ended(state));
+}
+
+/// A path in `dir` for a file called `name` that does not exist yet, numbering the name if needed
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(pos) if pos > 0 => (&name[..pos], &name[pos..]),
+        _ => (name, "")
+    };
+    (1..)
+        .map(|i| dir.join(format!("{}.{}{}", stem, i, extension)))
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ruostepurkki-dl-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn wait_for(manager: &mut Manager, id: usize) {
+        let started = Instant::now();
+        while manager.get(id).unwrap().state == State::Running {
+            manager.poll();
+            assert!(started.elapsed() < Duration::from_secs(5), "download did not end");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn start(manager: &mut Manager, dir: &Path, name: &str, contents: Vec<u8>, limit: Option<usize>) -> usize {
+        let path = dir.join(name);
+        let file = fs::File::create(&path).unwrap();
+        let body = protocol::Body::new(Box::new(io::Cursor::new(contents)), limit);
+        let target = Target {
+            url: "gemini://example.com/file".to_string(),
+            mime: "application/octet-stream".to_string(),
+            path,
+            open_with: None
+        };
+        manager.start(target, file, body, Arc::new(AtomicBool::new(false)))
+    }
+
+    #[test]
+    fn concurrent_downloads() {
+        let dir = test_dir("concurrent");
+        let mut manager = Manager::new();
+
+        let first = start(&mut manager, &dir, "first.bin", vec![1; 100_000], None);
+        let second = start(&mut manager, &dir, "second.bin", vec![2; 10], None);
+        assert_ne!(first, second);
+        assert!(manager.is_active());
+
+        wait_for(&mut manager, first);
+        wait_for(&mut manager, second);
+        assert!(!manager.is_active());
+
+        let download = manager.get(first).unwrap();
+        assert_eq!(download.state, State::Finished);
+        assert_eq!(download.received, 100_000);
+        assert_eq!(fs::read(&download.path).unwrap(), vec![1; 100_000]);
+        assert_eq!(fs::read(dir.join("second.bin")).unwrap(), vec![2; 10]);
+        assert!(!manager.cancel(first));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_downloads_are_removed() {
+        let dir = test_dir("failed");
+        let mut manager = Manager::new();
+
+        let id = start(&mut manager, &dir, "big.bin", vec![0; 1000], Some(100));
+        wait_for(&mut manager, id);
+        assert!(matches!(manager.get(id).unwrap().state, State::Failed(_)));
+        assert!(!dir.join("big.bin").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stopping_removes_partial_files() {
+        let dir = test_dir("stop");
+        let mut manager = Manager::new();
+
+        // A body that never ends, like a slow server
+        let body = protocol::Body::new(Box::new(io::repeat(7)), None);
+        let path = dir.join("endless.bin");
+        let file = fs::File::create(&path).unwrap();
+        let target = Target {
+            url: "gemini://example.com/endless".to_string(),
+            mime: "application/octet-stream".to_string(),
+            path: path.clone(),
+            open_with: None
+        };
+        let id = manager.start(target, file, body, Arc::new(AtomicBool::new(false)));
+        let done = start(&mut manager, &dir, "done.bin", vec![1; 10], None);
+        wait_for(&mut manager, done);
+
+        manager.stop_all(Duration::from_secs(5));
+        assert!(!manager.is_active());
+        assert_eq!(manager.get(id).unwrap().state, State::Cancelled);
+        assert!(!path.exists());
+        assert!(dir.join("done.bin").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unique_paths() {
+        let dir = test_dir("unique");
+        assert_eq!(unique_path(&dir, "cat.png"), dir.join("cat.png"));
+
+        fs::write(dir.join("cat.png"), b"").unwrap();
+        fs::write(dir.join("cat.1.png"), b"").unwrap();
+        fs::write(dir.join("README"), b"").unwrap();
+        assert_eq!(unique_path(&dir, "cat.png"), dir.join("cat.2.png"));
+        assert_eq!(unique_path(&dir, "README"), dir.join("README.1"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+extern crate mime;
+extern crate openssl;
+
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+/// Directory of the files made by `create_temp_file`, once there are any
+static TEMP_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// External program for opening documents of some MIME types, configured like a mailcap entry
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handler {
+    /// Either a full type such as `image/png`, `type/*` or `*/*`
+    pub pattern: String,
+    /// Shell command, with `%s` replaced by the file to open
+    pub command: String,
+    /// Whether the program runs in the terminal and the browser has to wait for it
+    pub needs_terminal: bool
+}
+
+/// Parses `pattern; command[; needsterminal]`
+pub fn parse_handler(value: &str) -> Result<Handler, String> {
+    let mut fields = value.split(';').map(|f| f.trim());
+
+    let pattern = match fields.next() {
+        Some(p) if p.contains('/') => p.to_lowercase(),
+        _ => { return Err(format!("'{}' does not start with a MIME type", value)); }
+    };
+    let command = match fields.next() {
+        Some(c) if !c.is_empty() => c.to_string(),
+        _ => { return Err(format!("No command given for {}", pattern)); }
+    };
+
+    let mut needs_terminal = false;
+    for flag in fields {
+        match flag {
+            "needsterminal" => { needs_terminal = true; }
+            "" => {}
+            _ => { return Err(format!("Unknown handler flag '{}'", flag)); }
+        }
+    }
+
+    Ok(Handler { pattern, command, needs_terminal })
+}
+
+fn pattern_matches(pattern: &str, mime: &mime::Mime) -> bool {
+    let (type_, subtype) = match pattern.find('/') {
+        Some(pos) => (&pattern[..pos], &pattern[pos+1..]),
+        None => { return false; }
+    };
+
+    (type_ == "*" || type_ == mime.type_().as_str()) && (subtype == "*" || subtype == mime.subtype().as_str())
+}
+
+/// The first handler whose pattern covers the MIME type, ignoring its parameters
+pub fn find<'a>(handlers: &'a [Handler], mime: &str) -> Option<&'a Handler> {
+    let mime: mime::Mime = mime.trim().parse().ok()?;
+    handlers.iter().find(|h| pattern_matches(&h.pattern, &mime))
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// The shell command with the file filled in, appended to the end when there is no `%s`
+pub fn command_line(handler: &Handler, path: &Path) -> String {
+    let quoted = shell_quote(&path.to_string_lossy());
+    if handler.command.contains("%s") {
+        handler.command.replace("%s", &quoted)
+    } else {
+        format!("{} {}", handler.command, quoted)
+    }
+}
+
+/// Runs the handler and waits for it, letting it use the terminal
+pub fn run(handler: &Handler, path: &Path) -> io::Result<ExitStatus> {
+    Command::new("sh").arg("-c").arg(command_line(handler, path)).status()
+}
+
+/// Starts the handler in the background, without access to the terminal
+pub fn spawn_detached(handler: &Handler, path: &Path) -> io::Result<Child> {
+    Command::new("sh").arg("-c").arg(command_line(handler, path))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+/// Name of the document at the URL, from the last segment of its path
+pub fn file_name(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let path = path.split_once("://").map_or(path, |(_, rest)| rest);
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() && path.contains('/') => Some(name.to_string()),
+        _ => None
+    }
+}
+
+/// Makes the directory for the files handed to handlers during this run. It has a random
+/// name, so that no one else can create it first, and it is always a new one.
+fn create_temp_files_dir() -> io::Result<PathBuf> {
+    loop {
+        let mut bytes = [0u8; 8];
+        openssl::rand::rand_bytes(&mut bytes).map_err(io::Error::other)?;
+        let name: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let dir = env::temp_dir().join(format!("ruostepurkki-{}", name));
+        match create_private_dir(&dir) {
+            Ok(()) => { return Ok(dir); }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => { return Err(e); }
+        }
+    }
+}
+
+/// Creates a new file only readable by the user in a directory of its own under the temporary
+/// directory, keeping the extension of the URL so that handlers can recognise the file type
+pub fn create_temp_file(url: &str) -> io::Result<(PathBuf, fs::File)> {
+    let dir = {
+        let mut created = TEMP_DIR.lock().unwrap();
+        match created.as_ref() {
+            Some(d) => d.clone(),
+            None => {
+                let d = create_temp_files_dir()?;
+                *created = Some(d.clone());
+                d
+            }
+        }
+    };
+
+    let extension = file_name(url)
+        .and_then(|n| Path::new(&n).extension().map(|e| e.to_string_lossy().to_string()))
+        .map(|e| format!(".{}", e))
+        .unwrap_or_default();
+    let n = TEMP_FILES.fetch_add(1, Ordering::SeqCst);
+    let path = dir.join(format!("{}{}", n, extension));
+
+    let file = create_private(&path)?;
+    Ok((path, file))
+}
+
+/// Removes the files made by `create_temp_file`. Handlers that run in the background
+/// may read their file after starting, so this is left until the browser quits.
+pub fn remove_temp_files() {
+    if let Some(dir) = TEMP_DIR.lock().unwrap().take() {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::DirBuilder::new().create(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handler_entries() {
+        let h = parse_handler("image/*; feh %s").unwrap();
+        assert_eq!(h, Handler { pattern: "image/*".to_string(), command: "feh %s".to_string(), needs_terminal: false });
+
+        let h = parse_handler(" Audio/MPEG ; mpv --no-video %s ; needsterminal").unwrap();
+        assert_eq!(h.pattern, "audio/mpeg");
+        assert!(h.needs_terminal);
+
+        assert!(parse_handler("feh %s").is_err());
+        assert!(parse_handler("image/png;").is_err());
+        assert!(parse_handler("image/png; feh; sometimes").is_err());
+    }
+
+    #[test]
+    fn handler_lookup() {
+        let handlers = vec![
+            parse_handler("image/png; pngview").unwrap(),
+            parse_handler("image/*; feh").unwrap(),
+            parse_handler("*/*; xdg-open").unwrap()
+        ];
+        assert_eq!(find(&handlers, "image/png").unwrap().command, "pngview");
+        assert_eq!(find(&handlers, "IMAGE/JPEG").unwrap().command, "feh");
+        assert_eq!(find(&handlers, "application/pdf; name=x").unwrap().command, "xdg-open");
+        assert!(find(&handlers[..2], "audio/ogg").is_none());
+        assert!(find(&handlers, "not a mime").is_none());
+    }
+
+    #[test]
+    fn command_lines() {
+        let h = parse_handler("image/*; feh -F %s").unwrap();
+        assert_eq!(command_line(&h, Path::new("/tmp/a b.png")), "feh -F '/tmp/a b.png'");
+        let h = parse_handler("image/*; feh").unwrap();
+        assert_eq!(command_line(&h, Path::new("/tmp/it's.png")), "feh '/tmp/it'\\''s.png'");
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("gemini://example.com/pics/cat.png?size=2").as_deref(), Some("cat.png"));
+        assert_eq!(file_name("gemini://example.com/pics/").as_deref(), None);
+        assert_eq!(file_name("gemini://example.com").as_deref(), None);
+    }
+
+    #[test]
+    fn temp_files() {
+        let (first, _) = create_temp_file("gemini://example.com/cat.png").unwrap();
+        let (second, _) = create_temp_file("gemini://example.com/notes").unwrap();
+        assert_eq!(first.parent(), second.parent());
+        assert_eq!(first.extension().unwrap(), "png");
+        assert!(second.extension().is_none());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(first.parent().unwrap()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        remove_temp_files();
+        assert!(!first.parent().unwrap().exists());
+    }
+}
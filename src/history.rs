@@ -0,0 +1,134 @@
+use rusqlite;
+
+use crate::certificates;
+
+/// Longest list of visits returned by `recent` and `search`
+pub const MAX_VISITS: usize = 500;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Visit {
+    pub url: String,
+    pub title: Option<String>,
+    /// Unix timestamp of the visit
+    pub visited: i64
+}
+
+fn open_db() -> rusqlite::Result<rusqlite::Connection> {
+    let c = certificates::open_db()?;
+    create_table(&c)?;
+    Ok(c)
+}
+
+fn create_table(c: &rusqlite::Connection) -> rusqlite::Result<()> {
+    c.execute("CREATE TABLE IF NOT EXISTS visit (id INTEGER PRIMARY KEY, url TEXT NOT NULL, title TEXT, visited INTEGER NOT NULL);", rusqlite::NO_PARAMS)?;
+    c.execute("CREATE INDEX IF NOT EXISTS visit_time ON visit (visited);", rusqlite::NO_PARAMS)?;
+    Ok(())
+}
+
+/// Stores a visit to the URL at the current time
+pub fn record(url: &str, title: Option<&str>) -> Result<(), String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    record_in(&conn, url, title, certificates::now())
+}
+
+fn record_in(conn: &rusqlite::Connection, url: &str, title: Option<&str>, visited: i64) -> Result<(), String> {
+    conn.execute("INSERT INTO visit (url, title, visited) VALUES (?, ?, ?)", rusqlite::params![url, title, visited])
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn visit_from_row(r: &rusqlite::Row) -> rusqlite::Result<Visit> {
+    Ok(Visit {
+        url: r.get(0)?,
+        title: r.get(1)?,
+        visited: r.get(2)?
+    })
+}
+
+/// The latest visits, newest first
+pub fn recent() -> Result<Vec<Visit>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    search_in(&conn, "")
+}
+
+/// Visits whose URL or title contains the term, ignoring case, newest first
+pub fn search(term: &str) -> Result<Vec<Visit>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    search_in(&conn, term)
+}
+
+fn search_in(conn: &rusqlite::Connection, term: &str) -> Result<Vec<Visit>, String> {
+    let pattern = format!("%{}%", escape_like(term));
+    let mut stmt = conn.prepare("SELECT url, title, visited FROM visit
+                                 WHERE url LIKE ?1 ESCAPE '\\' OR title LIKE ?1 ESCAPE '\\'
+                                 ORDER BY visited DESC, id DESC LIMIT ?2")
+        .map_err(|e| e.to_string())?;
+    stmt.query_map(rusqlite::params![pattern, MAX_VISITS as i64], visit_from_row)
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Visit>>>())
+        .map_err(|e| e.to_string())
+}
+
+/// Makes the wildcards of LIKE match themselves
+fn escape_like(term: &str) -> String {
+    let mut escaped = String::new();
+    for c in term.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Groups visits that are in order by the UTC day they were made on
+pub fn by_day(visits: &[Visit]) -> Vec<(String, Vec<&Visit>)> {
+    let mut days: Vec<(String, Vec<&Visit>)> = Vec::new();
+    for visit in visits {
+        let day = certificates::format_time(visit.visited)[..10].to_string();
+        match days.last_mut() {
+            Some((d, v)) if *d == day => v.push(visit),
+            _ => days.push((day, vec![visit]))
+        }
+    }
+    days
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn visit_search() {
+        let conn = test_db();
+        record_in(&conn, "gemini://example.com/", Some("Example capsule"), 100).unwrap();
+        record_in(&conn, "gemini://example.org/50%_off", None, 200).unwrap();
+        record_in(&conn, "gemini://example.com/", Some("Example capsule"), 300).unwrap();
+
+        let all = search_in(&conn, "").unwrap();
+        assert_eq!(all.iter().map(|v| v.visited).collect::<Vec<i64>>(), vec![300, 200, 100]);
+
+        assert_eq!(search_in(&conn, "CAPSULE").unwrap().len(), 2);
+        assert_eq!(search_in(&conn, "example.org").unwrap()[0].title, None);
+        assert_eq!(search_in(&conn, "0%_").unwrap().len(), 1);
+        assert_eq!(search_in(&conn, "%").unwrap().len(), 1);
+        assert!(search_in(&conn, "missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn grouping_by_day() {
+        let visit = |visited: i64| Visit { url: "gemini://example.com/".to_string(), title: None, visited };
+        let visits = vec![visit(2 * 86400 + 5), visit(86400 + 10), visit(86400)];
+
+        let days = by_day(&visits);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].0, "1970-01-03");
+        assert_eq!(days[1].0, "1970-01-02");
+        assert_eq!(days[1].1.len(), 2);
+    }
+}
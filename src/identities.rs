@@ -0,0 +1,244 @@
+extern crate openssl;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::{X509, X509NameBuilder};
+
+use rusqlite;
+
+extern crate url;
+use url::Url;
+
+use crate::certificates;
+
+/// How long generated client certificates stay valid
+const VALID_DAYS: u32 = 3650;
+
+pub struct Identity {
+    pub name: String,
+    pub certificate: X509,
+    pub key: PKey<Private>
+}
+
+pub struct IdentityInfo {
+    pub name: String,
+    pub scopes: Vec<String>
+}
+
+fn open_db() -> rusqlite::Result<rusqlite::Connection> {
+    let c = certificates::open_db()?;
+    c.execute("CREATE TABLE IF NOT EXISTS identity (name TEXT PRIMARY KEY, certificate BLOB, key BLOB);", rusqlite::NO_PARAMS)?;
+    c.execute("CREATE TABLE IF NOT EXISTS identity_scope (scope TEXT PRIMARY KEY, name TEXT);", rusqlite::NO_PARAMS)?;
+    Ok(c)
+}
+
+/// Generates a new key pair and a self-signed certificate with the given common name
+pub fn generate_self_signed(common_name: &str) -> Result<(X509, PKey<Private>), ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name_builder = X509NameBuilder::new()?;
+    name_builder.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    let subject = name_builder.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(VALID_DAYS)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&subject)?;
+    builder.set_issuer_name(&subject)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((builder.build(), key))
+}
+
+pub fn create(name: &str) -> Result<Identity, String> {
+    let (certificate, key) = match generate_self_signed(name) {
+        Ok(r) => r,
+        Err(e) => { return Err(format!("Could not generate certificate: {}", e)); }
+    };
+
+    let cert_pem = certificate.to_pem().map_err(|e| e.to_string())?;
+    let key_pem = key.private_key_to_pem_pkcs8().map_err(|e| e.to_string())?;
+
+    let conn = open_db().map_err(|e| e.to_string())?;
+    match conn.execute("INSERT INTO identity (name, certificate, key) VALUES (?, ?, ?)", rusqlite::params![name, cert_pem, key_pem]) {
+        Ok(_) => {},
+        Err(e) => { return Err(format!("Could not store identity '{}': {}", name, e)); }
+    }
+
+    Ok(Identity {
+        name: name.to_string(),
+        certificate,
+        key
+    })
+}
+
+pub fn load(name: &str) -> Result<Option<Identity>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+
+    let stmt = "SELECT certificate, key FROM identity WHERE name=(?)";
+    let (cert_pem, key_pem): (Vec<u8>, Vec<u8>) = match conn.query_row(stmt, &[&name], |r| Ok((r.get(0)?, r.get(1)?))) {
+        Ok(r) => r,
+        Err(rusqlite::Error::QueryReturnedNoRows) => { return Ok(None); }
+        Err(e) => { return Err(e.to_string()); }
+    };
+
+    let certificate = X509::from_pem(&cert_pem).map_err(|e| e.to_string())?;
+    let key = PKey::private_key_from_pem(&key_pem).map_err(|e| e.to_string())?;
+
+    Ok(Some(Identity {
+        name: name.to_string(),
+        certificate,
+        key
+    }))
+}
+
+pub fn list() -> Result<Vec<IdentityInfo>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+
+    let mut stmt = conn.prepare("SELECT name FROM identity ORDER BY name").map_err(|e| e.to_string())?;
+    let names = stmt.query_map(rusqlite::NO_PARAMS, |r| r.get(0))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>())
+        .map_err(|e| e.to_string())?;
+
+    let mut scope_stmt = conn.prepare("SELECT scope FROM identity_scope WHERE name=(?) ORDER BY scope").map_err(|e| e.to_string())?;
+    let mut identities = Vec::new();
+    for name in names {
+        let scopes = scope_stmt.query_map(&[&name], |r| r.get(0))
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<String>>>())
+            .map_err(|e| e.to_string())?;
+        identities.push(IdentityInfo { name, scopes });
+    }
+
+    Ok(identities)
+}
+
+pub fn delete(name: &str) -> Result<bool, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    conn.execute("DELETE FROM identity_scope WHERE name=(?)", &[&name]).map_err(|e| e.to_string())?;
+    let deleted = conn.execute("DELETE FROM identity WHERE name=(?)", &[&name]).map_err(|e| e.to_string())?;
+    Ok(deleted > 0)
+}
+
+/// Makes the identity be presented for every URL under the given scope
+pub fn attach(name: &str, scope: &str) -> Result<(), String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    conn.execute("INSERT OR REPLACE INTO identity_scope (scope, name) VALUES (?, ?)", &[&scope, &name]).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Removes the scope that currently selects an identity for the URL, returning it
+pub fn detach(url: &Url) -> Result<Option<String>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    let scope = match matching_scope(&conn, url)? {
+        Some((scope, _)) => scope,
+        None => { return Ok(None); }
+    };
+    conn.execute("DELETE FROM identity_scope WHERE scope=(?)", &[&scope]).map_err(|e| e.to_string())?;
+    Ok(Some(scope))
+}
+
+/// Finds the identity whose most specific scope covers the URL
+pub fn for_url(url: &Url) -> Result<Option<Identity>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    match matching_scope(&conn, url)? {
+        Some((_, name)) => load(&name),
+        None => Ok(None)
+    }
+}
+
+fn matching_scope(conn: &rusqlite::Connection, url: &Url) -> Result<Option<(String, String)>, String> {
+    let mut stmt = conn.prepare("SELECT scope, name FROM identity_scope").map_err(|e| e.to_string())?;
+    let scopes = stmt.query_map(rusqlite::NO_PARAMS, |r| Ok((r.get(0)?, r.get(1)?)))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<(String, String)>>>())
+        .map_err(|e| e.to_string())?;
+
+    Ok(best_scope(scopes, url.as_str()))
+}
+
+fn best_scope(scopes: Vec<(String, String)>, url: &str) -> Option<(String, String)> {
+    scopes.into_iter()
+        .filter(|(scope, _)| scope_matches(scope, url))
+        .max_by_key(|(scope, _)| scope.len())
+}
+
+fn scope_matches(scope: &str, url: &str) -> bool {
+    if !url.starts_with(scope) {
+        return false;
+    }
+
+    if scope.ends_with('/') {
+        return true;
+    }
+
+    matches!(url[scope.len()..].chars().next(), None | Some('/') | Some('?'))
+}
+
+/// Scope covering every path on the URL's host
+pub fn host_scope(url: &Url) -> String {
+    let mut scope = url.clone();
+    scope.set_path("/");
+    scope.set_query(None);
+    scope.set_fragment(None);
+    scope.to_string()
+}
+
+/// Scope covering the URL's path and everything below it
+pub fn path_scope(url: &Url) -> String {
+    let mut scope = url.clone();
+    scope.set_query(None);
+    scope.set_fragment(None);
+    scope.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scope_matching() {
+        assert!(scope_matches("gemini://example.com/", "gemini://example.com/"));
+        assert!(scope_matches("gemini://example.com/", "gemini://example.com/app/login"));
+        assert!(scope_matches("gemini://example.com/app", "gemini://example.com/app/login"));
+        assert!(scope_matches("gemini://example.com/app", "gemini://example.com/app?query"));
+        assert!(!scope_matches("gemini://example.com/app", "gemini://example.com/apple"));
+        assert!(!scope_matches("gemini://example.com/", "gemini://example.org/"));
+
+        let scopes = vec![
+            ("gemini://example.com/".to_string(), "site".to_string()),
+            ("gemini://example.com/app".to_string(), "app".to_string()),
+        ];
+        assert_eq!(best_scope(scopes.clone(), "gemini://example.com/app/x").unwrap().1, "app");
+        assert_eq!(best_scope(scopes.clone(), "gemini://example.com/other").unwrap().1, "site");
+        assert!(best_scope(scopes, "gemini://example.org/").is_none());
+    }
+
+    #[test]
+    fn scopes_from_url() {
+        let url = Url::parse("gemini://example.com:1966/app/login?user#top").unwrap();
+        assert_eq!(host_scope(&url), "gemini://example.com:1966/");
+        assert_eq!(path_scope(&url), "gemini://example.com:1966/app/login");
+    }
+
+    #[test]
+    fn self_signed_certificate() {
+        let (cert, key) = generate_self_signed("tester").unwrap();
+        let cn = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap();
+        assert_eq!(cn.data().as_utf8().unwrap().to_string(), "tester");
+        assert!(cert.verify(&key).unwrap());
+    }
+}
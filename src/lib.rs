@@ -0,0 +1,10 @@
+pub mod document;
+pub mod protocol;
+pub mod certificates;
+pub mod identities;
+pub mod config;
+pub mod redirects;
+pub mod handlers;
+pub mod downloads;
+pub mod bookmarks;
+pub mod history;
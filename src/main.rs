@@ -1,9 +1,73 @@
-mod document;
-mod protocol;
-mod certificates;
 mod ui;
 
+use ruostepurkki::{bookmarks, certificates, config, document, downloads, handlers, history, identities, protocol, redirects};
+
+use std::env;
+use std::path::PathBuf;
+
+const USAGE: &str = "Usage: ruostepurkki [options]
+
+Options:
+    -d, --data-dir DIR    store the database in DIR (default: $XDG_DATA_HOME/ruostepurkki)
+    -c, --config FILE     read settings from FILE (default: $XDG_CONFIG_HOME/ruostepurkki/config)
+    -h, --help            show this help
+
+The environment variables RUOSTEPURKKI_DATA_DIR and RUOSTEPURKKI_CONFIG
+can be used instead of the options.";
+
+fn parse_args(args: &[String]) -> Result<Option<config::Overrides>, String> {
+    let mut overrides = config::Overrides::default();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => { return Ok(None); }
+            "-d" | "--data-dir" => {
+                match iter.next() {
+                    Some(dir) => { overrides.data_dir = Some(PathBuf::from(dir)); }
+                    None => { return Err(format!("{} needs a directory", arg)); }
+                }
+            }
+            "-c" | "--config" => {
+                match iter.next() {
+                    Some(file) => { overrides.config_file = Some(PathBuf::from(file)); }
+                    None => { return Err(format!("{} needs a file", arg)); }
+                }
+            }
+            _ => { return Err(format!("Unknown argument: {}", arg)); }
+        }
+    }
+
+    Ok(Some(overrides))
+}
+
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let overrides = match parse_args(&args) {
+        Ok(Some(o)) => o,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let configuration = match config::load(overrides) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error in configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = config::prepare_data_dir(&configuration) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    config::init(configuration).unwrap();
+
     if let Ok(mut ui) = ui::TextUI::init() {
         match ui.main_loop() {
             Ok(()) => {}
@@ -13,4 +77,5 @@ fn main() {
             }
         }
     }
+    handlers::remove_temp_files();
 }
@@ -1,13 +1,25 @@
 extern crate openssl;
-use openssl::ssl::{SslMethod, SslConnector, SslVerifyMode};
+use openssl::ssl::{HandshakeError, SslMethod, SslConnector, SslVerifyMode};
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 extern crate url;
-use url::Url;
+use url::{Host, Url};
+
+extern crate idna;
+
+extern crate percent_encoding;
+use percent_encoding::percent_decode_str;
 
 use crate::certificates;
+use crate::identities;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StatusCode {
@@ -31,6 +43,31 @@ pub enum StatusCode {
     CertNotValid            = 62,
 }
 
+impl StatusCode {
+    pub fn description(&self) -> &'static str {
+        match self {
+            StatusCode::Input => "Input",
+            StatusCode::SensitiveInput => "Sensitive input",
+            StatusCode::Success => "Success",
+            StatusCode::RedirectTemp => "Temporary redirect",
+            StatusCode::RedirectPerm => "Permanent redirect",
+            StatusCode::TemporaryFailure => "Temporary failure",
+            StatusCode::ServerUnavailable => "Server unavailable",
+            StatusCode::CgiError => "CGI error",
+            StatusCode::ProxyError => "Proxy error",
+            StatusCode::SlowDown => "Slow down",
+            StatusCode::PermanentFailure => "Permanent failure",
+            StatusCode::NotFound => "Not found",
+            StatusCode::Gone => "Gone",
+            StatusCode::ProxyReqRefused => "Proxy request refused",
+            StatusCode::BadRequest => "Bad request",
+            StatusCode::ClientCertRequired => "Client certificate required",
+            StatusCode::CertNotAuthorized => "Certificate not authorised",
+            StatusCode::CertNotValid => "Certificate not valid",
+        }
+    }
+}
+
 fn statuscode_from_u8(i: u8) -> Option<StatusCode> {
     let code = match i {
         10 => Some(StatusCode::Input),
@@ -66,7 +103,7 @@ pub enum Response {
     Input(String),
     SensitiveInput(String),
 
-    Success(String, Vec<u8>),
+    Success(String, Body),
 
     RedirectTemp(String),
     RedirectPerm(String),
@@ -88,123 +125,626 @@ pub enum Response {
     CertNotValid(Option<String>)
 }
 
-fn parse_response_header(res: &str) -> Result<ResponseHeader, String> {
-    if res.len() < 2 {
-        return Err("No status code in response".to_string());
+/// Body of a successful response, read straight from the connection
+pub struct Body {
+    reader: Box<dyn Read + Send>,
+    limit: Option<usize>,
+    received: usize,
+    progress: Option<Sender<Progress>>
+}
+
+impl Body {
+    /// Wraps a reader, failing once more than `limit` bytes have been read from it
+    pub fn new(reader: Box<dyn Read + Send>, limit: Option<usize>) -> Self {
+        Body {
+            reader,
+            limit,
+            received: 0,
+            progress: None
+        }
     }
 
-    let codeint = match res[0..2].parse::<u8>() {
-        Ok(c) => c,
-        Err(_) => { return Err("Couldn't parse status code".to_string()); }
-    };
+    /// Changes how many bytes may be read in total, such as when the body turns out to be a download
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.received += n;
 
-    let meta;
-    if res.len() > 3 {
-        meta = Some(res[3..].to_string());
-    } else {
-        meta = None;
+        if let Some(limit) = self.limit {
+            if self.received > limit {
+                return Err(io::Error::other(TooLargeError(limit)));
+            }
+        }
+        if n > 0 {
+            if let Some(tx) = &self.progress {
+                let _ = tx.send(Progress::Received(self.received));
+            }
+        }
+
+        Ok(n)
     }
-    
-    let code = match statuscode_from_u8(codeint) {
-        Some(c) => c,
-        None => { return Err(format!("Status code {} not known", codeint)); }
-    };
+}
 
-    return Ok(ResponseHeader{status: code, meta: meta});
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Body {{ received: {}, limit: {:?} }}", self.received, self.limit)
+    }
 }
 
-pub fn make_request(raw_url: &str) -> Result<Response, String> {
-    let mut request_url = raw_url;
-    let mut gemini_scheme = "gemini://".to_string();
-    let url = match Url::parse(raw_url) {
-        Ok(u) => u,
-        Err(url::ParseError::RelativeUrlWithoutBase) => {
-            gemini_scheme.push_str(raw_url);
-            match Url::parse(&gemini_scheme) {
-                Ok(u) => {
-                    request_url = &gemini_scheme;
-                    u
-                },
-                Err(_) => { return Err("Failed parsing URL".to_string()); }
+impl Response {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Response::Input(_) => StatusCode::Input,
+            Response::SensitiveInput(_) => StatusCode::SensitiveInput,
+            Response::Success(_, _) => StatusCode::Success,
+            Response::RedirectTemp(_) => StatusCode::RedirectTemp,
+            Response::RedirectPerm(_) => StatusCode::RedirectPerm,
+            Response::TemporaryFailure(_) => StatusCode::TemporaryFailure,
+            Response::ServerUnavailable(_) => StatusCode::ServerUnavailable,
+            Response::CgiError(_) => StatusCode::CgiError,
+            Response::ProxyError(_) => StatusCode::ProxyError,
+            Response::SlowDown(_) => StatusCode::SlowDown,
+            Response::PermanentFailure(_) => StatusCode::PermanentFailure,
+            Response::NotFound(_) => StatusCode::NotFound,
+            Response::Gone(_) => StatusCode::Gone,
+            Response::ProxyReqRefused(_) => StatusCode::ProxyReqRefused,
+            Response::BadRequest(_) => StatusCode::BadRequest,
+            Response::ClientCertRequired(_) => StatusCode::ClientCertRequired,
+            Response::CertNotAuthorized(_) => StatusCode::CertNotAuthorized,
+            Response::CertNotValid(_) => StatusCode::CertNotValid,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UrlParse(url::ParseError),
+    UnsupportedScheme(String),
+    Dns { host: String, source: io::Error },
+    Connect { address: String, source: io::Error },
+    Tls(openssl::ssl::Error),
+    CertUntrusted {
+        host: String,
+        port: u16,
+        known_digest: Vec<u8>,
+        cert: Box<certificates::CertInfo>
+    },
+    CertMissing,
+    Database(String),
+    Identity(String),
+    HeaderTooLong,
+    BadHeader(String),
+    InvalidStatus(String),
+    Timeout(io::Error),
+    Cancelled,
+    TooLarge(usize),
+    Io(io::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UrlParse(_) => write!(f, "Invalid URL"),
+            Error::UnsupportedScheme(s) => write!(f, "Scheme '{}' is not supported", s),
+            Error::Dns { host, .. } => write!(f, "Could not resolve host {}", host),
+            Error::Connect { address, .. } => write!(f, "Could not connect to {}", address),
+            Error::Tls(_) => write!(f, "TLS handshake failed"),
+            Error::CertUntrusted { host, port, .. } => write!(f, "Certificate for {}:{} has changed", host, port),
+            Error::CertMissing => write!(f, "Server did not present a certificate"),
+            Error::Database(e) => write!(f, "Certificate database error: {}", e),
+            Error::Identity(e) => write!(f, "Could not use client certificate: {}", e),
+            Error::HeaderTooLong => write!(f, "Response header is too long"),
+            Error::BadHeader(e) => write!(f, "Malformed response header: {}", e),
+            Error::InvalidStatus(e) => write!(f, "Invalid status code: {}", e),
+            Error::Timeout(_) => write!(f, "Request timed out"),
+            Error::Cancelled => write!(f, "Request cancelled"),
+            Error::TooLarge(limit) => write!(f, "Response is larger than the limit of {} bytes", limit),
+            Error::Io(_) => write!(f, "Connection error")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::UrlParse(e) => Some(e),
+            Error::Dns { source, .. } => Some(source),
+            Error::Connect { source, .. } => Some(source),
+            Error::Tls(e) => Some(e),
+            Error::Timeout(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::TimedOut {
+            Error::Timeout(e)
+        } else if e.get_ref().is_some_and(|inner| inner.is::<CancelledError>()) {
+            Error::Cancelled
+        } else if let Some(TooLargeError(limit)) = e.get_ref().and_then(|inner| inner.downcast_ref::<TooLargeError>()) {
+            Error::TooLarge(*limit)
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+/// Marker carried inside an io::Error when a request is cancelled mid-read
+#[derive(Debug)]
+struct CancelledError;
+
+impl fmt::Display for CancelledError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for CancelledError {}
+
+/// Marker carried inside an io::Error when a body grows past its size limit
+#[derive(Debug)]
+struct TooLargeError(usize);
+
+impl fmt::Display for TooLargeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "more than {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for TooLargeError {}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Self {
+        Error::UrlParse(e)
+    }
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_TOTAL_TIMEOUT: Duration = Duration::from_secs(120);
+pub const DEFAULT_MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+/// Stages of a request reported back to the caller while it runs
+#[derive(Clone, PartialEq, Debug)]
+pub enum Progress {
+    Resolving(String),
+    Connecting(String),
+    Handshake,
+    Waiting,
+    Received(usize)
+}
+
+/// Longest meta string allowed in a response header, in bytes
+const MAX_META_LENGTH: usize = 1024;
+
+/// How often blocked socket operations wake up to check for cancellation and deadlines
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone)]
+pub struct RequestOptions {
+    /// Certificate digests the user has chosen to trust for this session, by host and port
+    pub accepted_certs: HashMap<(String, u16), Vec<u8>>,
+    /// Time allowed for establishing the TCP connection
+    pub connect_timeout: Option<Duration>,
+    /// Time the server may stay silent while a response is expected
+    pub read_timeout: Option<Duration>,
+    /// Time allowed for the whole request, from connecting to the end of the body
+    pub total_timeout: Option<Duration>,
+    /// Largest response body accepted, in bytes
+    pub max_body_size: Option<usize>,
+    /// Set to true from another thread to abort the request
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Set to true from another thread to let the rest of the body take longer than `total_timeout`
+    pub lift_deadline: Option<Arc<AtomicBool>>,
+    /// Receives progress updates while the request runs
+    pub progress: Option<Sender<Progress>>
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        RequestOptions {
+            accepted_certs: HashMap::new(),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            total_timeout: Some(DEFAULT_TOTAL_TIMEOUT),
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            cancel: None,
+            lift_deadline: None,
+            progress: None
+        }
+    }
+}
+
+/// Enforces the cancellation flag and the timeouts of a request
+struct Watchdog {
+    deadline: Option<Instant>,
+    read_timeout: Option<Duration>,
+    cancel: Option<Arc<AtomicBool>>,
+    lift_deadline: Option<Arc<AtomicBool>>
+}
+
+impl Watchdog {
+    fn new(options: &RequestOptions) -> Self {
+        Watchdog {
+            deadline: options.total_timeout.map(|t| Instant::now() + t),
+            read_timeout: options.read_timeout,
+            cancel: options.cancel.clone(),
+            lift_deadline: options.lift_deadline.clone()
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed))
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match &self.lift_deadline {
+            Some(l) if l.load(Ordering::Relaxed) => None,
+            _ => self.deadline
+        }
+    }
+
+    /// Time left until the deadline, limited to `limit`
+    fn remaining(&self, limit: Option<Duration>) -> Option<Duration> {
+        let left = self.deadline().map(|d| d.saturating_duration_since(Instant::now()));
+        match (left, limit) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b)
+        }
+    }
+
+    /// Fails if the request was cancelled, ran past its deadline or has been waiting since `idle_since` for too long
+    fn check(&self, idle_since: Instant) -> io::Result<()> {
+        if self.cancelled() {
+            return Err(io::Error::other(CancelledError));
+        }
+        if self.deadline().is_some_and(|d| Instant::now() >= d) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the request took too long"));
+        }
+        if self.read_timeout.is_some_and(|t| idle_since.elapsed() >= t) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the server stopped responding"));
+        }
+        Ok(())
+    }
+}
+
+fn report(options: &RequestOptions, progress: Progress) {
+    if let Some(tx) = &options.progress {
+        let _ = tx.send(progress);
+    }
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Stream wrapper that retries reads and writes interrupted by the socket timeout until the watchdog gives up
+struct GuardedStream<S> {
+    inner: S,
+    watchdog: Watchdog
+}
+
+impl<S: Read> Read for GuardedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            self.watchdog.check(started)?;
+            match self.inner.read(buf) {
+                Err(ref e) if would_block(e) => {},
+                r => { return r; }
             }
         }
-        Err(_e) => { return Err("Failed parsing URL".to_string()); }
-    };
+    }
+}
 
-    let scheme = url.scheme();
-    if scheme != "gemini" {
-        return Err("Scheme not supported".to_string());
+impl<S: Write> Write for GuardedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            self.watchdog.check(started)?;
+            match self.inner.write(buf) {
+                Err(ref e) if would_block(e) => {},
+                r => { return r; }
+            }
+        }
     }
 
-    let host = match url.host_str() {
-        Some(h) => h,
-        None => { return Err("Did not find hostname".to_string()); }
-    };
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-    let port = match url.port() {
-        Some(p) => p,
-        None => match scheme {
-            "gemini" => 1965,
-            _ => {return Err("No port known for given scheme".to_string())}
+fn connect(addresses: &[SocketAddr], options: &RequestOptions, watchdog: &Watchdog) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+
+    for address in addresses {
+        if watchdog.cancelled() {
+            return Err(io::Error::other(CancelledError));
+        }
+
+        let result = match watchdog.remaining(options.connect_timeout) {
+            Some(t) if t == Duration::from_secs(0) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+            }
+            Some(t) => TcpStream::connect_timeout(address, t),
+            None => TcpStream::connect(address)
+        };
+
+        match result {
+            Ok(s) => { return Ok(s); }
+            Err(e) => { last_error = e; }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Unknown status codes are handled like the basic code of their category
+fn statuscode_fallback(i: u8) -> Option<StatusCode> {
+    match i / 10 {
+        1 => Some(StatusCode::Input),
+        2 => Some(StatusCode::Success),
+        3 => Some(StatusCode::RedirectTemp),
+        4 => Some(StatusCode::TemporaryFailure),
+        5 => Some(StatusCode::PermanentFailure),
+        6 => Some(StatusCode::ClientCertRequired),
+        _ => None
+    }
+}
+
+/// Reads the header line up to CRLF, leaving everything after it in the reader
+fn read_header<R: BufRead>(reader: &mut R) -> Result<String, Error> {
+    // Two digit status, a space, the meta and CRLF
+    let max_length = 2 + 1 + MAX_META_LENGTH + 2;
+    let mut header = Vec::new();
+
+    loop {
+        let byte = match reader.fill_buf()? {
+            [] => { return Err(Error::BadHeader("Connection closed before the end of the header".to_string())); }
+            buf => buf[0]
+        };
+        reader.consume(1);
+        header.push(byte);
+
+        if header.ends_with(b"\r\n") {
+            header.truncate(header.len() - 2);
+            break;
         }
+        if header.len() >= max_length {
+            return Err(Error::HeaderTooLong);
+        }
+    }
+
+    match String::from_utf8(header) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(Error::BadHeader("Header is not valid UTF-8".to_string()))
+    }
+}
+
+fn parse_response_header(res: &str) -> Result<ResponseHeader, Error> {
+    if res.len() < 2 {
+        return Err(Error::BadHeader("No status code in response".to_string()));
+    }
+
+    let codeint = match res.get(0..2) {
+        Some(c) if c.bytes().all(|b| b.is_ascii_digit()) => c.parse::<u8>().unwrap_or(0),
+        _ => { return Err(Error::InvalidStatus(res.chars().take(2).collect())); }
     };
 
-    let mut builder = match SslConnector::builder(SslMethod::tls()) {
-        Ok(b) => b,
-        Err(_) => { return Err("Error creating SSL connector builder".to_string()) }
+    let meta = match &res[2..] {
+        "" => None,
+        rest => match rest.strip_prefix(' ') {
+            Some(m) => Some(m.to_string()),
+            None => { return Err(Error::BadHeader("Status code must be followed by a space".to_string())); }
+        }
     };
-    builder.set_verify(SslVerifyMode::NONE);
-    let connector = builder.build();
-    let stream = match TcpStream::connect(format!("{}:{}", host, port)) {
-        Ok(s) => s,
-        Err(_) => { return Err("Unable to start TLS connection".to_string()); }
+
+    if meta.as_ref().is_some_and(|m| m.len() > MAX_META_LENGTH) {
+        return Err(Error::HeaderTooLong);
+    }
+
+    let code = match statuscode_from_u8(codeint).or_else(|| statuscode_fallback(codeint)) {
+        Some(c) => c,
+        None => { return Err(Error::InvalidStatus(format!("{} is not a known status", codeint))); }
     };
-    let mut stream = match connector.connect(host, stream) {
-        Ok(s) => s,
-        Err(_) => { return Err("Unable to connect".to_string()); }
+
+    Ok(ResponseHeader{status: code, meta})
+}
+
+/// Parses what the user typed or a link pointed to, assuming gemini:// when no scheme is given
+pub fn parse_url(raw_url: &str) -> Result<Url, Error> {
+    let url = match Url::parse(raw_url.trim()) {
+        Ok(u) => u,
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            let mut gemini_scheme = "gemini://".to_string();
+            gemini_scheme.push_str(raw_url.trim());
+            Url::parse(&gemini_scheme)?
+        }
+        Err(e) => { return Err(e.into()); }
     };
 
-    match certificates::check_cert(&stream, &host) {
-        Ok(_) => (),
-        Err(_) => return Err("Certificate error".to_string())
+    normalize_url(url)
+}
+
+/// Brings a gemini:// URL to a canonical form, so that the same page always gives the same request
+pub fn normalize_url(mut url: Url) -> Result<Url, Error> {
+    if url.scheme() != "gemini" {
+        return Ok(url);
     }
 
-    let mut req = request_url.clone().to_string();
-    req.push_str("\r\n");
-    let req = req.into_bytes();
-    match stream.write_all(&req) {
-        Ok(_) => {},
-        Err(_) => { return Err("Error writing to stream".to_string()); }
+    // Gemini is not a scheme the url crate knows, so the host is left as it was written
+    if let Some(Host::Domain(domain)) = url.host() {
+        let decoded = percent_decode_str(domain).decode_utf8().map_err(|_| Error::UrlParse(url::ParseError::IdnaError))?;
+        let ascii = idna::domain_to_ascii(&decoded).map_err(|_| Error::UrlParse(url::ParseError::IdnaError))?;
+        url.set_host(Some(&ascii))?;
     }
 
-    let mut buf = vec![0u8; 1029];
-    let read = match stream.read(&mut buf) {
-        Ok(r) => r,
-        Err(_) => { return Err("Error reading header from stream".to_string()); }
-    };
+    if url.port() == Some(1965) {
+        let _ = url.set_port(None);
+    }
+    if url.path().is_empty() {
+        url.set_path("/");
+    }
 
-    if read == 1029 && buf[1027..] != [13, 10] {
-        return Err("Too long header received".to_string());
+    let path = uppercase_escapes(url.path());
+    url.set_path(&path);
+    if let Some(query) = url.query().map(uppercase_escapes) {
+        url.set_query(Some(&query));
     }
+    url.set_fragment(None);
+
+    Ok(url)
+}
 
-    let header;
-    // Strip out CRLF from end of header
-    if read > 2 && buf[read-2] == '\r' as u8 && buf[read-1] == '\n' as u8 {
-        header = buf[..read-2].to_vec();
-    } else {
-        header = buf[..read].to_vec();
+/// Writes percent-encoded bytes with uppercase hex digits, so %c3%a4 and %C3%A4 compare equal
+fn uppercase_escapes(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut escape = 0;
+    for c in s.chars() {
+        if c == '%' {
+            escape = 2;
+            result.push(c);
+        } else if escape > 0 {
+            escape -= 1;
+            result.push(c.to_ascii_uppercase());
+        } else {
+            result.push(c);
+        }
     }
+    result
+}
 
-    let headerstr = match String::from_utf8(header) {
-        Ok(s) => s,
-        Err(_) => { return Err("Could not parse header as UTF-8".to_string()); }
-    };
+/// Stream a request is sent and its response read over
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+/// Opens the transport for a request
+pub trait Connector {
+    fn connect(&self, url: &Url, host: &str, port: u16, options: &RequestOptions) -> Result<Box<dyn Transport>, Error>;
+}
+
+/// Connects over TLS, checking the server certificate against the pinned ones and
+/// presenting the identity attached to the URL
+pub struct TlsConnector;
+
+impl Connector for TlsConnector {
+    fn connect(&self, url: &Url, host: &str, port: u16, options: &RequestOptions) -> Result<Box<dyn Transport>, Error> {
+        let mut builder = match SslConnector::builder(SslMethod::tls()) {
+            Ok(b) => b,
+            Err(e) => { return Err(Error::Tls(e.into())); }
+        };
+        builder.set_verify(SslVerifyMode::NONE);
+        if let Some(identity) = identities::for_url(url).map_err(Error::Identity)? {
+            if let Err(e) = builder.set_certificate(&identity.certificate).and_then(|_| builder.set_private_key(&identity.key)) {
+                return Err(Error::Identity(format!("{}: {}", identity.name, e)));
+            }
+        }
+        let connector = builder.build();
+
+        report(options, Progress::Resolving(host.to_string()));
+        let addresses = match (host, port).to_socket_addrs() {
+            Ok(a) => a.collect::<Vec<_>>(),
+            Err(e) => { return Err(Error::Dns { host: host.to_string(), source: e }); }
+        };
+        let watchdog = Watchdog::new(options);
+        report(options, Progress::Connecting(format!("{}:{}", host, port)));
+        let stream = match connect(&addresses, options, &watchdog) {
+            Ok(s) => s,
+            Err(e) => {
+                return match Error::from(e) {
+                    Error::Cancelled => Err(Error::Cancelled),
+                    Error::Io(e) | Error::Timeout(e) => Err(Error::Connect { address: format!("{}:{}", host, port), source: e }),
+                    other => Err(other)
+                };
+            }
+        };
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_write_timeout(Some(POLL_INTERVAL))?;
+
+        report(options, Progress::Handshake);
+        let handshake_started = Instant::now();
+        let mut handshake = connector.connect(host, stream);
+        let stream = loop {
+            match handshake {
+                Ok(s) => { break s; }
+                Err(HandshakeError::WouldBlock(mid)) => {
+                    watchdog.check(handshake_started)?;
+                    handshake = mid.handshake();
+                }
+                Err(HandshakeError::SetupFailure(e)) => { return Err(Error::Tls(e.into())); }
+                Err(HandshakeError::Failure(mid)) => { return Err(Error::Tls(mid.into_error())); }
+            }
+        };
+
+        let accepted = options.accepted_certs.get(&(host.to_string(), port)).map(|d| d.as_slice());
+        match certificates::check_cert(&stream, host, port, accepted) {
+            Ok(_) => (),
+            Err(certificates::ServerCertError::CertChanged { known_digest, cert }) => {
+                return Err(Error::CertUntrusted {
+                    host: host.to_string(),
+                    port,
+                    known_digest,
+                    cert
+                });
+            }
+            Err(certificates::ServerCertError::CertNotPresent) => {
+                return Err(Error::CertMissing);
+            }
+            Err(certificates::ServerCertError::Database(e)) => {
+                return Err(Error::Database(e));
+            }
+        }
+
+        Ok(Box::new(GuardedStream {
+            inner: stream,
+            watchdog
+        }))
+    }
+}
+
+pub fn make_request(raw_url: &str, options: &RequestOptions) -> Result<Response, Error> {
+    request_with(&TlsConnector, raw_url, options)
+}
+
+/// Sends the request over a transport opened by `connector` and reads the response
+pub fn request_with<C: Connector>(connector: &C, raw_url: &str, options: &RequestOptions) -> Result<Response, Error> {
+    let url = parse_url(raw_url)?;
+    let request_url = url.as_str();
 
-    let header = match parse_response_header(&headerstr) {
-        Ok(h) => h,
-        Err(e) => { return Err(e.to_string()); }
+    let scheme = url.scheme();
+    if scheme != "gemini" {
+        return Err(Error::UnsupportedScheme(scheme.to_string()));
+    }
+
+    let host = match url.host_str() {
+        Some(h) => h,
+        None => { return Err(Error::UrlParse(url::ParseError::EmptyHost)); }
     };
 
+    let port = url.port().unwrap_or(1965);
+
+    let mut stream = connector.connect(&url, host, port, options)?;
+
+    let mut req = request_url.to_string();
+    req.push_str("\r\n");
+    let req = req.into_bytes();
+    stream.write_all(&req)?;
+    report(options, Progress::Waiting);
+
+    // Bytes read past the header stay buffered and become the start of the body
+    let mut stream = BufReader::new(stream);
+    let headerstr = read_header(&mut stream)?;
+    let header = parse_response_header(&headerstr)?;
+
     let response;
 
     match header.status {
@@ -225,20 +765,20 @@ pub fn make_request(raw_url: &str) -> Result<Response, String> {
             response = Response::SensitiveInput(meta);
         },
         StatusCode::Success => {
-            let mut content_buffer = Vec::<u8>::new();
-            stream.read_to_end(&mut content_buffer).unwrap();
+            let mut body = Body::new(Box::new(stream), options.max_body_size);
+            body.progress = options.progress.clone();
 
             let metadata = match header.meta {
                 Some(m) => m,
                 None => String::new()
             };
 
-            response = Response::Success(metadata, content_buffer);
+            response = Response::Success(metadata, body);
         },
         StatusCode::RedirectTemp => {
             let meta = match header.meta {
                 Some(m) => m,
-                None => { return Err("Server returned status code for redirect but no URL provided".to_string()); }
+                None => { return Err(Error::BadHeader("Redirect without a target URL".to_string())); }
             };
 
             response = Response::RedirectTemp(meta);
@@ -246,7 +786,7 @@ pub fn make_request(raw_url: &str) -> Result<Response, String> {
         StatusCode::RedirectPerm => {
             let meta = match header.meta {
                 Some(m) => m,
-                None => { return Err("Server returned status code for redirect but no URL provided".to_string()); }
+                None => { return Err(Error::BadHeader("Redirect without a target URL".to_string())); }
             };
 
             response = Response::RedirectPerm(meta);
@@ -299,6 +839,7 @@ pub fn make_request(raw_url: &str) -> Result<Response, String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
 
     #[test]
     fn parse_header() {
@@ -327,4 +868,296 @@ mod tests {
         assert!(header.status == StatusCode::RedirectTemp);
         assert!(header.meta == Some("gemini://new.example.com/".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn header_errors() {
+        assert!(matches!(parse_response_header("2"), Err(Error::BadHeader(_))));
+        assert!(matches!(parse_response_header("xx text/gemini"), Err(Error::InvalidStatus(_))));
+        assert!(matches!(parse_response_header("99"), Err(Error::InvalidStatus(_))));
+        assert!(matches!(parse_response_header("+1"), Err(Error::InvalidStatus(_))));
+        assert!(matches!(parse_response_header("20text/gemini"), Err(Error::BadHeader(_))));
+
+        let long_meta = format!("20 {}", "a".repeat(MAX_META_LENGTH + 1));
+        assert!(matches!(parse_response_header(&long_meta), Err(Error::HeaderTooLong)));
+        let longest_meta = format!("20 {}", "a".repeat(MAX_META_LENGTH));
+        assert!(parse_response_header(&longest_meta).is_ok());
+    }
+
+    #[test]
+    fn unknown_status_codes() {
+        assert!(parse_response_header("12 Name").unwrap().status == StatusCode::Input);
+        assert!(parse_response_header("21 text/plain").unwrap().status == StatusCode::Success);
+        assert!(parse_response_header("39 gemini://example.com/").unwrap().status == StatusCode::RedirectTemp);
+        assert!(parse_response_header("49").unwrap().status == StatusCode::TemporaryFailure);
+        assert!(parse_response_header("58").unwrap().status == StatusCode::PermanentFailure);
+        assert!(parse_response_header("69").unwrap().status == StatusCode::ClientCertRequired);
+        assert!(matches!(parse_response_header("00"), Err(Error::InvalidStatus(_))));
+        assert!(matches!(parse_response_header("70"), Err(Error::InvalidStatus(_))));
+    }
+
+    /// Hands out at most `size` bytes per read, like a header split over several TCP segments
+    struct Segmented {
+        data: io::Cursor<Vec<u8>>,
+        size: usize
+    }
+
+    impl Read for Segmented {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = std::cmp::min(buf.len(), self.size);
+            self.data.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn header_reading() {
+        let data = b"20 text/gemini\r\n# Title\r\nBody".to_vec();
+        let mut reader = BufReader::new(Segmented { data: io::Cursor::new(data), size: 3 });
+        assert_eq!(read_header(&mut reader).unwrap(), "20 text/gemini");
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "# Title\r\nBody");
+
+        let mut reader = io::Cursor::new(b"51 Not found\r\n".to_vec());
+        assert_eq!(read_header(&mut reader).unwrap(), "51 Not found");
+
+        let mut reader = io::Cursor::new(b"20 text/gemini".to_vec());
+        assert!(matches!(read_header(&mut reader), Err(Error::BadHeader(_))));
+
+        let mut reader = io::Cursor::new(b"20 text/gemini\n".to_vec());
+        assert!(matches!(read_header(&mut reader), Err(Error::BadHeader(_))));
+
+        let mut reader = io::Cursor::new(b"\xff\xfe\r\n".to_vec());
+        assert!(matches!(read_header(&mut reader), Err(Error::BadHeader(_))));
+
+        let longest = format!("20 {}\r\n", "a".repeat(MAX_META_LENGTH));
+        assert!(read_header(&mut io::Cursor::new(longest.into_bytes())).is_ok());
+        let too_long = format!("20 {}\r\n", "a".repeat(MAX_META_LENGTH + 1));
+        assert!(matches!(read_header(&mut io::Cursor::new(too_long.into_bytes())), Err(Error::HeaderTooLong)));
+    }
+
+    /// Connector that replays a canned response and records what was sent to it
+    struct ScriptedConnector {
+        response: Vec<u8>,
+        sent: Arc<Mutex<Vec<u8>>>,
+        connected: Mutex<Option<(String, u16)>>
+    }
+
+    impl ScriptedConnector {
+        fn new(response: &[u8]) -> Self {
+            ScriptedConnector {
+                response: response.to_vec(),
+                sent: Arc::new(Mutex::new(Vec::new())),
+                connected: Mutex::new(None)
+            }
+        }
+    }
+
+    struct ScriptedStream {
+        response: io::Cursor<Vec<u8>>,
+        sent: Arc<Mutex<Vec<u8>>>
+    }
+
+    impl Read for ScriptedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connector for ScriptedConnector {
+        fn connect(&self, _url: &Url, host: &str, port: u16, _options: &RequestOptions) -> Result<Box<dyn Transport>, Error> {
+            *self.connected.lock().unwrap() = Some((host.to_string(), port));
+            Ok(Box::new(ScriptedStream {
+                response: io::Cursor::new(self.response.clone()),
+                sent: self.sent.clone()
+            }))
+        }
+    }
+
+    fn scripted(response: &str) -> Result<Response, Error> {
+        request_with(&ScriptedConnector::new(response.as_bytes()), "gemini://example.com/page", &RequestOptions::default())
+    }
+
+    #[test]
+    fn request_line() {
+        let connector = ScriptedConnector::new(b"20 text/gemini\r\n");
+        request_with(&connector, "example.com", &RequestOptions::default()).unwrap();
+        assert_eq!(&*connector.sent.lock().unwrap(), b"gemini://example.com/\r\n");
+        assert_eq!(*connector.connected.lock().unwrap(), Some(("example.com".to_string(), 1965)));
+
+        let connector = ScriptedConnector::new(b"20 text/gemini\r\n");
+        request_with(&connector, "gemini://example.com:1966/a b?q", &RequestOptions::default()).unwrap();
+        assert_eq!(&*connector.sent.lock().unwrap(), b"gemini://example.com:1966/a%20b?q\r\n");
+        assert_eq!(*connector.connected.lock().unwrap(), Some(("example.com".to_string(), 1966)));
+
+        let connector = ScriptedConnector::new(b"20 text/gemini\r\n");
+        let result = request_with(&connector, "https://example.com/", &RequestOptions::default());
+        assert!(matches!(result, Err(Error::UnsupportedScheme(s)) if s == "https"));
+        assert!(connector.connected.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn every_status_code() {
+        let codes = [
+            StatusCode::Input, StatusCode::SensitiveInput, StatusCode::Success,
+            StatusCode::RedirectTemp, StatusCode::RedirectPerm,
+            StatusCode::TemporaryFailure, StatusCode::ServerUnavailable, StatusCode::CgiError,
+            StatusCode::ProxyError, StatusCode::SlowDown,
+            StatusCode::PermanentFailure, StatusCode::NotFound, StatusCode::Gone,
+            StatusCode::ProxyReqRefused, StatusCode::BadRequest,
+            StatusCode::ClientCertRequired, StatusCode::CertNotAuthorized, StatusCode::CertNotValid
+        ];
+        for code in codes.iter() {
+            let response = scripted(&format!("{} meta\r\n", *code as u8)).unwrap();
+            assert_eq!(response.status(), *code);
+        }
+    }
+
+    #[test]
+    fn response_contents() {
+        match scripted("10 Your name?\r\n").unwrap() {
+            Response::Input(prompt) => assert_eq!(prompt, "Your name?"),
+            _ => panic!("expected input")
+        }
+        match scripted("11\r\n").unwrap() {
+            Response::SensitiveInput(prompt) => assert_eq!(prompt, ""),
+            _ => panic!("expected sensitive input")
+        }
+        match scripted("20 text/gemini; lang=fi\r\n# Otsikko\r\nteksti").unwrap() {
+            Response::Success(mime, mut body) => {
+                assert_eq!(mime, "text/gemini; lang=fi");
+                let mut contents = String::new();
+                body.read_to_string(&mut contents).unwrap();
+                assert_eq!(contents, "# Otsikko\r\nteksti");
+            }
+            _ => panic!("expected success")
+        }
+        match scripted("44 30\r\n").unwrap() {
+            Response::SlowDown(meta) => assert_eq!(meta.as_deref(), Some("30")),
+            _ => panic!("expected slow down")
+        }
+        match scripted("51\r\n").unwrap() {
+            Response::NotFound(meta) => assert_eq!(meta, None),
+            _ => panic!("expected not found")
+        }
+    }
+
+    #[test]
+    fn redirects() {
+        match scripted("30 gemini://example.org/new\r\n").unwrap() {
+            Response::RedirectTemp(target) => assert_eq!(target, "gemini://example.org/new"),
+            _ => panic!("expected temporary redirect")
+        }
+        match scripted("31 /moved\r\n").unwrap() {
+            Response::RedirectPerm(target) => assert_eq!(target, "/moved"),
+            _ => panic!("expected permanent redirect")
+        }
+        assert!(matches!(scripted("30\r\n"), Err(Error::BadHeader(_))));
+        assert!(matches!(scripted("31\r\n"), Err(Error::BadHeader(_))));
+    }
+
+    #[test]
+    fn malformed_headers() {
+        assert!(matches!(scripted(""), Err(Error::BadHeader(_))));
+        assert!(matches!(scripted("20 text/gemini"), Err(Error::BadHeader(_))));
+        assert!(matches!(scripted("2\r\n"), Err(Error::BadHeader(_))));
+        assert!(matches!(scripted("ab text/gemini\r\n"), Err(Error::InvalidStatus(_))));
+        assert!(matches!(scripted("95 what\r\n"), Err(Error::InvalidStatus(_))));
+        assert!(matches!(scripted(&format!("20 {}\r\n", "a".repeat(2000))), Err(Error::HeaderTooLong)));
+    }
+
+    #[test]
+    fn body_limit_applies_to_requests() {
+        let options = RequestOptions {
+            max_body_size: Some(4),
+            ..Default::default()
+        };
+        let connector = ScriptedConnector::new(b"20 text/plain\r\nmore than four bytes");
+        match request_with(&connector, "gemini://example.com/", &options).unwrap() {
+            Response::Success(_, mut body) => {
+                let err = Error::from(body.read_to_end(&mut Vec::new()).unwrap_err());
+                assert!(matches!(err, Error::TooLarge(4)));
+            }
+            _ => panic!("expected success")
+        }
+    }
+
+    #[test]
+    fn url_normalization() {
+        let normalized = |s: &str| parse_url(s).unwrap().to_string();
+
+        assert_eq!(normalized("example.com"), "gemini://example.com/");
+        assert_eq!(normalized("  gemini://example.com  "), "gemini://example.com/");
+        assert_eq!(normalized("GEMINI://Example.COM"), "gemini://example.com/");
+        assert_eq!(normalized("gemini://example.com:1965/page"), "gemini://example.com/page");
+        assert_eq!(normalized("gemini://example.com:1966/page"), "gemini://example.com:1966/page");
+        assert_eq!(normalized("gemini://example.com/page#section"), "gemini://example.com/page");
+        assert_eq!(normalized("gemini://example.com/a b/ä?q=ö"), "gemini://example.com/a%20b/%C3%A4?q=%C3%B6");
+        assert_eq!(normalized("gemini://example.com/%c3%a4"), "gemini://example.com/%C3%A4");
+        assert_eq!(normalized("gemini://bücher.example/"), "gemini://xn--bcher-kva.example/");
+        assert_eq!(normalized("gemini://BÜCHER.example/"), "gemini://xn--bcher-kva.example/");
+        assert_eq!(normalized("gemini://127.0.0.1:1965/"), "gemini://127.0.0.1/");
+        assert_eq!(normalized("gemini://[::1]/"), "gemini://[::1]/");
+
+        // Other schemes are left to their own rules
+        assert_eq!(normalized("https://example.com/page#section"), "https://example.com/page#section");
+
+        assert_eq!(uppercase_escapes("%e9%8c%86-abc"), "%E9%8C%86-abc");
+    }
+
+    #[test]
+    fn error_sources() {
+        let err = parse_url("gemini://exa mple.com/").unwrap_err();
+        assert!(matches!(err, Error::UrlParse(_)));
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+        assert_eq!(std::error::Error::source(&err).unwrap().to_string(), "eof");
+        assert!(std::error::Error::source(&Error::HeaderTooLong).is_none());
+    }
+
+    #[test]
+    fn body_size_limit() {
+        let mut contents = Vec::new();
+        let mut body = Body::new(Box::new(io::Cursor::new(vec![b'x'; 100])), Some(100));
+        body.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 100);
+
+        let mut body = Body::new(Box::new(io::Cursor::new(vec![b'x'; 101])), Some(100));
+        let err = Error::from(body.read_to_end(&mut contents).unwrap_err());
+        assert!(matches!(err, Error::TooLarge(100)));
+
+        let mut body = Body::new(Box::new(io::Cursor::new(vec![b'x'; 101])), None);
+        assert_eq!(body.read_to_end(&mut Vec::new()).unwrap(), 101);
+
+        let mut body = Body::new(Box::new(io::Cursor::new(vec![b'x'; 101])), Some(100));
+        body.set_limit(Some(200));
+        assert_eq!(body.read_to_end(&mut Vec::new()).unwrap(), 101);
+    }
+
+    #[test]
+    fn lifted_deadline() {
+        let lift = Arc::new(AtomicBool::new(false));
+        let options = RequestOptions {
+            total_timeout: Some(Duration::from_secs(0)),
+            lift_deadline: Some(lift.clone()),
+            ..Default::default()
+        };
+        let watchdog = Watchdog::new(&options);
+        assert!(watchdog.check(Instant::now()).is_err());
+
+        lift.store(true, Ordering::Relaxed);
+        assert!(watchdog.check(Instant::now()).is_ok());
+        assert_eq!(watchdog.remaining(None), None);
+    }
+}
@@ -0,0 +1,149 @@
+use rusqlite;
+
+extern crate url;
+use url::Url;
+
+use crate::certificates;
+use crate::protocol;
+
+/// Longest chain of redirects followed for a single request
+pub const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    /// Target is on the same scheme, host and port, follow it without asking
+    SameSite,
+    /// Target is on another site, ask before following
+    OtherSite,
+    /// Target has already been visited in this chain
+    Loop,
+    TooMany
+}
+
+/// Resolves a possibly relative redirect target against the URL that was requested
+pub fn resolve(from: &Url, target: &str) -> Result<Url, String> {
+    from.join(target.trim())
+        .map_err(protocol::Error::from)
+        .and_then(protocol::normalize_url)
+        .map_err(|e| format!("Invalid redirect target '{}': {}", target, e))
+}
+
+/// Decides how to treat a redirect from `from` to `to`, given the URLs already redirected through
+pub fn check(chain: &[String], from: &Url, to: &Url) -> Check {
+    if to == from || chain.iter().any(|u| u == to.as_str()) {
+        return Check::Loop;
+    }
+    if chain.len() >= MAX_REDIRECTS {
+        return Check::TooMany;
+    }
+
+    if to.scheme() == from.scheme() && to.host_str() == from.host_str() && to.port() == from.port() {
+        Check::SameSite
+    } else {
+        Check::OtherSite
+    }
+}
+
+fn open_db() -> rusqlite::Result<rusqlite::Connection> {
+    let c = certificates::open_db()?;
+    create_table(&c)?;
+    Ok(c)
+}
+
+pub(crate) fn create_table(c: &rusqlite::Connection) -> rusqlite::Result<()> {
+    c.execute("CREATE TABLE IF NOT EXISTS redirect (source TEXT PRIMARY KEY, target TEXT);", rusqlite::NO_PARAMS)?;
+    Ok(())
+}
+
+/// Remembers a permanent redirect so later visits go straight to the target
+pub fn remember(source: &str, target: &str) -> Result<(), String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    remember_in(&conn, source, target)
+}
+
+pub(crate) fn remember_in(conn: &rusqlite::Connection, source: &str, target: &str) -> Result<(), String> {
+    conn.execute("INSERT OR REPLACE INTO redirect (source, target) VALUES (?, ?)", &[&source, &target])
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Where a URL has permanently moved to, following earlier moves of the target as well
+pub fn lookup(url: &str) -> Result<Option<String>, String> {
+    let conn = open_db().map_err(|e| e.to_string())?;
+    lookup_in(&conn, url)
+}
+
+pub(crate) fn lookup_in(conn: &rusqlite::Connection, url: &str) -> Result<Option<String>, String> {
+    let mut seen = vec![url.to_string()];
+
+    while seen.len() <= MAX_REDIRECTS {
+        let current = &seen[seen.len() - 1];
+        let target: String = match conn.query_row("SELECT target FROM redirect WHERE source=(?)", &[current], |r| r.get(0)) {
+            Ok(t) => t,
+            Err(rusqlite::Error::QueryReturnedNoRows) => { break; }
+            Err(e) => { return Err(e.to_string()); }
+        };
+        if seen.contains(&target) {
+            break;
+        }
+        seen.push(target);
+    }
+
+    if seen.len() > 1 {
+        Ok(seen.pop())
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn relative_targets() {
+        let from = url("gemini://example.com/dir/page.gmi");
+        assert_eq!(resolve(&from, "other.gmi").unwrap().as_str(), "gemini://example.com/dir/other.gmi");
+        assert_eq!(resolve(&from, "/top").unwrap().as_str(), "gemini://example.com/top");
+        assert_eq!(resolve(&from, "//example.org/x").unwrap().as_str(), "gemini://example.org/x");
+        assert_eq!(resolve(&from, "gemini://example.net").unwrap().as_str(), "gemini://example.net/");
+        assert_eq!(resolve(&from, "gemini://Example.com:1965/x#y").unwrap().as_str(), "gemini://example.com/x");
+    }
+
+    #[test]
+    fn redirect_policy() {
+        let from = url("gemini://example.com/a");
+        assert_eq!(check(&[], &from, &url("gemini://example.com/b")), Check::SameSite);
+        assert_eq!(check(&[], &from, &url("gemini://example.org/b")), Check::OtherSite);
+        assert_eq!(check(&[], &from, &url("gemini://example.com:1966/b")), Check::OtherSite);
+        assert_eq!(check(&[], &from, &url("https://example.com/b")), Check::OtherSite);
+
+        assert_eq!(check(&[], &from, &from), Check::Loop);
+        let chain = vec!["gemini://example.com/b".to_string(), "gemini://example.com/a".to_string()];
+        assert_eq!(check(&chain, &from, &url("gemini://example.com/b")), Check::Loop);
+
+        let chain = (0..MAX_REDIRECTS).map(|i| format!("gemini://example.com/{}", i)).collect::<Vec<String>>();
+        assert_eq!(check(&chain, &from, &url("gemini://example.com/b")), Check::TooMany);
+    }
+
+    #[test]
+    fn permanent_redirects() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+
+        assert_eq!(lookup_in(&conn, "gemini://example.com/old").unwrap(), None);
+
+        remember_in(&conn, "gemini://example.com/old", "gemini://example.com/new").unwrap();
+        remember_in(&conn, "gemini://example.com/new", "gemini://example.org/").unwrap();
+        assert_eq!(lookup_in(&conn, "gemini://example.com/old").unwrap().as_deref(), Some("gemini://example.org/"));
+        assert_eq!(lookup_in(&conn, "gemini://example.com/new").unwrap().as_deref(), Some("gemini://example.org/"));
+
+        // A stored loop ends where it would start over
+        remember_in(&conn, "gemini://example.org/", "gemini://example.com/old").unwrap();
+        assert_eq!(lookup_in(&conn, "gemini://example.com/old").unwrap().as_deref(), Some("gemini://example.org/"));
+    }
+}
@@ -1,6 +1,13 @@
-use std::io::{stdout, Write};
+use std::fs;
+use std::io::{stdout, Read, Write};
 
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 extern crate crossterm;
 use crossterm::{
@@ -28,9 +35,13 @@ use regex::Regex;
 extern crate url;
 use url::Url;
 
+extern crate percent_encoding;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
 use crate::protocol;
 use protocol::{
-    Response
+    Response,
+    StatusCode
 };
 
 use crate::document;
@@ -38,15 +49,93 @@ use document::{
     Line,
 };
 
+use crate::identities;
+
+use crate::certificates;
+
+use crate::config;
+
+use crate::redirects;
+
+use crate::handlers;
+
+use crate::downloads;
+
+use crate::bookmarks;
+
+use crate::history;
+
+/// Characters left unescaped in user input sent as a query string (RFC 3986 unreserved)
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
 
 #[derive(PartialEq, Debug)]
 enum Command {
     Go(String),
     Quit,
     Link(usize),
+    Identity(IdentityCommand),
+    Certs(CertsCommand),
+    Bookmark(BookmarkCommand),
+    Tab(TabCommand),
+    /// Lists past visits, only those matching the term if one is given
+    History(Option<String>),
+    Reload,
+    CancelDownload(usize),
+    /// Writes the current page to a file, as received or as shown on screen
+    Save { path: Option<String>, rendered: bool },
     Unknown(String)
 }
 
+#[derive(PartialEq, Debug)]
+enum CertsCommand {
+    List,
+    Show(String),
+    Forget(String),
+    Import(String),
+    Export(String)
+}
+
+#[derive(PartialEq, Debug)]
+enum TabCommand {
+    /// Opens a tab after the current one, optionally going to a link number or an address in it
+    New(Option<String>),
+    /// Switches to the tab with the number, counting from 1
+    Switch(usize),
+    /// Closes the tab with the number, or the current one
+    Close(Option<usize>),
+    /// Moves the current tab to the position
+    Move(usize)
+}
+
+#[derive(PartialEq, Debug)]
+enum BookmarkCommand {
+    List,
+    /// Bookmarks the current page, titled by its first heading unless a title is given
+    Add(Option<String>),
+    Delete(i64),
+    Rename(i64, String),
+    Tag(i64, Vec<String>)
+}
+
+#[derive(PartialEq, Debug)]
+enum IdentityCommand {
+    List,
+    New(String),
+    Use(String, IdentityScope),
+    Detach,
+    Delete(String)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum IdentityScope {
+    Host,
+    Path
+}
+
 #[derive(Clone)]
 struct PrintableLine {
     s: String,
@@ -66,26 +155,33 @@ struct ContentContainer {
     right_margin: usize,
     scroll_row: usize,
     scroll_column: usize,
-    links: Option<Vec<String>>
+    links: Option<Vec<String>>,
+    /// Language of the contents, from the lang parameter of the MIME type
+    lang: Option<String>
 }
 
 impl ContentContainer {
     pub fn new() -> ContentContainer {
         let size = terminal::size().unwrap();
+        ContentContainer::with_size(size.0, size.1)
+    }
 
+    /// A container for a terminal of the given size, leaving room for the top and bottom rows
+    pub fn with_size(width: u16, height: u16) -> ContentContainer {
         let new_container = ContentContainer {
             lines: Vec::<PrintableLine>::new(),
             rendered: Vec::<String>::new(),
             content_width: 0,
-            width: size.0 as usize,
-            height: size.1 as usize - 2,
+            width: width as usize,
+            height: height as usize - 2,
             top_margin: 1,
             bottom_margin: 1,
             left_margin: 0,
             right_margin: 0,
             scroll_row: 0,
             scroll_column: 0,
-            links: None
+            links: None,
+            lang: None
         };
 
         new_container
@@ -143,6 +239,12 @@ impl ContentContainer {
         (self.scroll_row, self.scroll_column)
     }
 
+    /// Scrolls to the given position, or as close to it as the contents allow
+    pub fn set_scroll_pos(&mut self, row: usize, column: usize) {
+        self.scroll_row = std::cmp::min(row, self.rendered.len().saturating_sub(self.height));
+        self.scroll_column = std::cmp::min(column, self.content_width.saturating_sub(self.width));
+    }
+
     pub fn set_margins(&mut self, top: usize, bottom: usize, left: usize, right: usize) {
         self.top_margin = top;
         self.bottom_margin = bottom;
@@ -202,6 +304,13 @@ impl ContentContainer {
         }
     }
 
+    /// The contents as currently wrapped for the screen
+    pub fn rendered_text(&self) -> String {
+        let mut text = self.rendered.join("\n");
+        text.push('\n');
+        text
+    }
+
     pub fn set_contents_text(&mut self, text: &str) {
         let mut contents = Vec::<PrintableLine>::new();
         for l in text.lines() {
@@ -212,6 +321,7 @@ impl ContentContainer {
         }
 
         self.lines = contents;
+        self.lang = None;
         self.scroll_row = 0;
         self.scroll_column = 0;
         self.render();
@@ -263,6 +373,7 @@ impl ContentContainer {
         }
 
         self.links = Some(links);
+        self.lang = None;
         self.scroll_row = 0;
         self.scroll_column = 0;
         self.lines = contents;
@@ -270,42 +381,77 @@ impl ContentContainer {
     }
 }
 
+#[derive(Clone)]
 enum TextPage {
     Gemini(Vec<document::Line>),
     Plain(String)
 }
 
+/// A document as kept in the history
+#[derive(Clone)]
+struct Page {
+    contents: TextPage,
+    lang: Option<String>,
+    /// The body as it was received, before decoding
+    raw: Vec<u8>
+}
+
+/// A visited page and where the user was on it
+#[derive(Clone, Debug, PartialEq)]
+struct HistoryEntry {
+    url: String,
+    /// Row and column the page was scrolled to when leaving it
+    scroll: (usize, usize),
+    /// Number of the link last followed from the page
+    link: Option<usize>
+}
+
 struct GeminiHistory {
-    urlhistory: Vec<String>,
-    current: usize,
-    cache: HashMap<String, TextPage>
+    entries: Vec<HistoryEntry>,
+    current: usize
 }
 
 impl GeminiHistory {
     pub fn new() -> Self {
         GeminiHistory {
-            urlhistory: Vec::new(),
-            current: 0,
-            cache: HashMap::new()
+            entries: Vec::new(),
+            current: 0
         }
     }
 
-    pub fn insert(&mut self, url: String, page: TextPage) {
-        self.urlhistory.truncate(self.current+1);
-        self.urlhistory.push(url.clone());
-        self.current = self.urlhistory.len()-1;
-        self.cache.insert(url, page);
+    /// Makes the URL the current page, dropping the pages that could be gone forwards to
+    pub fn push(&mut self, url: String) {
+        self.entries.truncate(self.current+1);
+        self.entries.push(HistoryEntry { url, scroll: (0, 0), link: None });
+        self.current = self.entries.len()-1;
     }
 
-    pub fn get_from_cache(&self, url: String) -> Option<&TextPage> {
-        self.cache.get(&url)
+    /// Changes the URL of the current page, keeping its place in the history
+    pub fn replace_current(&mut self, url: String) {
+        match self.entries.get_mut(self.current) {
+            Some(e) => { e.url = url; }
+            None => { self.push(url); }
+        }
     }
 
     pub fn get_current_url(&self) -> Option<String> {
-        if self.urlhistory.len() > self.current {
-            Some(self.urlhistory[self.current].to_string())
-        } else {
-            None
+        self.current_entry().map(|e| e.url.clone())
+    }
+
+    pub fn current_entry(&self) -> Option<&HistoryEntry> {
+        self.entries.get(self.current)
+    }
+
+    /// Remembers the scroll position of the current page, to be restored when coming back to it
+    pub fn set_scroll(&mut self, scroll: (usize, usize)) {
+        if let Some(e) = self.entries.get_mut(self.current) {
+            e.scroll = scroll;
+        }
+    }
+
+    pub fn set_link(&mut self, link: usize) {
+        if let Some(e) = self.entries.get_mut(self.current) {
+            e.link = Some(link);
         }
     }
 
@@ -318,7 +464,7 @@ impl GeminiHistory {
     }
 
     pub fn go_forwards(&mut self) -> bool {
-        if self.current < self.urlhistory.len()-1 {
+        if self.current + 1 < self.entries.len() {
             self.current +=1;
             return true;
         }
@@ -326,12 +472,343 @@ impl GeminiHistory {
     }
 }
 
-pub struct TextUI {
+/// Minimum time between re-rendering a page that is still loading
+const RENDER_INTERVAL: Duration = Duration::from_millis(250);
+
+struct CacheEntry {
+    page: Page,
+    /// Bytes counted against the size of the cache
+    size: usize,
+    fetched: Instant,
+    /// Value of the cache's use counter when the entry was last inserted or read
+    used: u64
+}
+
+/// Pages kept for going back and forth in the history, dropping the least recently
+/// used ones once they take more than `max_bytes`
+struct PageCache {
+    entries: HashMap<String, CacheEntry>,
+    max_bytes: Option<usize>,
+    total_bytes: usize,
+    uses: u64
+}
+
+impl PageCache {
+    pub fn new(max_bytes: Option<usize>) -> Self {
+        PageCache {
+            entries: HashMap::new(),
+            max_bytes,
+            total_bytes: 0,
+            uses: 0
+        }
+    }
+
+    pub fn insert(&mut self, url: &str, page: Page) {
+        self.remove(url);
+        self.uses += 1;
+        let size = page.raw.len();
+        self.entries.insert(url.to_string(), CacheEntry { page, size, fetched: Instant::now(), used: self.uses });
+        self.total_bytes += size;
+        self.evict(url);
+    }
+
+    /// The cached page and when it was fetched, counting as a use of the entry
+    pub fn get(&mut self, url: &str) -> Option<(&Page, Instant)> {
+        self.uses += 1;
+        let uses = self.uses;
+        self.entries.get_mut(url).map(|e| {
+            e.used = uses;
+            (&e.page, e.fetched)
+        })
+    }
+
+    fn remove(&mut self, url: &str) {
+        if let Some(e) = self.entries.remove(url) {
+            self.total_bytes -= e.size;
+        }
+    }
+
+    /// Drops the least recently used entries until the cache fits, never the one for `keep`
+    fn evict(&mut self, keep: &str) {
+        let max = match self.max_bytes {
+            Some(m) => m,
+            None => { return; }
+        };
+
+        while self.total_bytes > max {
+            let oldest = self.entries.iter()
+                .filter(|(url, _)| url.as_str() != keep)
+                .min_by_key(|(_, e)| e.used)
+                .map(|(url, _)| url.clone());
+            match oldest {
+                Some(url) => self.remove(&url),
+                None => { break; }
+            }
+        }
+    }
+}
+
+/// How a page is being opened
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Navigation {
+    /// Following a link or going to an address, always fetched
+    New,
+    /// Going back or forwards, from the cache when it still has the page
+    History,
+    /// Fetching the current page again
+    Reload
+}
+
+/// Messages from the threads working on a request
+enum WorkerMessage {
+    Response(std::result::Result<Response, protocol::Error>),
+    Data(Vec<u8>),
+    End(Option<protocol::Error>)
+}
+
+/// A text document whose body is still arriving
+struct PartialPage {
+    gemini: bool,
+    charset: Option<String>,
+    lang: Option<String>,
+    raw: Vec<u8>,
+    changed: bool,
+    rendered_at: Option<Instant>
+}
+
+/// A request running on worker threads, polled from the main loop
+struct PendingRequest {
+    url: String,
+    cancel: Arc<AtomicBool>,
+    progress: mpsc::Receiver<protocol::Progress>,
+    sender: mpsc::Sender<WorkerMessage>,
+    receiver: mpsc::Receiver<WorkerMessage>,
+    /// Lets the body outlast the total timeout when it turns out to be a download
+    lift_deadline: Arc<AtomicBool>,
+    page: Option<PartialPage>,
+    navigation: Navigation,
+    /// URLs already redirected through on the way to `url`
+    redirects: Vec<String>,
+    /// A response that arrived while the tab was not shown, handled once it is
+    parked: Option<std::result::Result<Response, protocol::Error>>
+}
+
+impl PendingRequest {
+    /// Reads a text body on its own thread, to be shown as it arrives
+    fn stream_text(&mut self, mime: &str, body: protocol::Body) {
+        let sender = self.sender.clone();
+        thread::spawn(move || stream_body(body, sender));
+
+        self.page = Some(PartialPage {
+            gemini: document::is_gemini_doc(mime),
+            charset: document::mime_param(mime, "charset"),
+            lang: document::mime_param(mime, "lang"),
+            raw: Vec::new(),
+            changed: false,
+            rendered_at: None
+        });
+    }
+
+    fn take_data(&mut self, bytes: &[u8]) {
+        if let Some(page) = &mut self.page {
+            page.raw.extend_from_slice(bytes);
+            page.changed = true;
+        }
+    }
+}
+
+/// A tab with its own history, the page it shows and the request loading in it
+struct Tab {
     top_line: String,
     container: ContentContainer,
+    history: GeminiHistory,
+    /// The page of the current history entry, kept apart from the cache that other tabs fill
+    page: Option<Page>,
+    /// Carries on while other tabs are shown
+    pending: Option<PendingRequest>
+}
+
+impl Tab {
+    pub fn new(container: ContentContainer) -> Self {
+        Tab {
+            top_line: String::new(),
+            container,
+            history: GeminiHistory::new(),
+            page: None,
+            pending: None
+        }
+    }
+
+    /// Aborts the pending request, if there is one
+    pub fn cancel_request(&mut self) -> bool {
+        match self.pending.take() {
+            Some(p) => {
+                p.cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn show_page(&mut self, url: &str, page: &Page, keep_scroll: bool) {
+        let scroll = self.container.scroll_pos();
+        match &page.contents {
+            TextPage::Gemini(v) => self.container.set_contents_gemini(v),
+            TextPage::Plain(s) => self.container.set_contents_text(s)
+        }
+        self.container.lang = page.lang.clone();
+        self.top_line = url.to_string();
+        if keep_scroll {
+            self.container.set_scroll_pos(scroll.0, scroll.1);
+        }
+    }
+
+    /// Remembers where the current history entry is scrolled to
+    pub fn save_scroll(&mut self) {
+        self.history.set_scroll(self.container.scroll_pos());
+    }
+
+    /// Scrolls to where the current history entry was left
+    pub fn restore_position(&mut self) {
+        if let Some(entry) = self.history.current_entry() {
+            self.container.set_scroll_pos(entry.scroll.0, entry.scroll.1);
+        }
+    }
+
+    /// Makes the page the current one, as a new step in the history unless it replaces
+    /// the page that was already there. Going back or forwards to a page that redirects
+    /// elsewhere keeps its entry, now with the URL the page came from. Only complete pages
+    /// are cached; for an incomplete one an older copy is dropped too, so going back or
+    /// forwards fetches the page again.
+    pub fn add_to_history(&mut self, cache: &mut PageCache, url: &str, page: Page, complete: bool, navigation: Navigation) {
+        if navigation == Navigation::History {
+            self.history.replace_current(url.to_string());
+        } else if navigation == Navigation::New || self.history.get_current_url().as_deref() != Some(url) {
+            self.history.push(url.to_string());
+        }
+        if complete {
+            cache.insert(url, page.clone());
+        } else {
+            cache.remove(url);
+        }
+        self.page = Some(page);
+    }
+
+    /// Shows a page made up by the browser for the URL, such as an error, in place of the
+    /// one fetched from it. It takes its step in the history like any page but is not
+    /// cached, and with no document behind it there is nothing to save.
+    pub fn show_generated_page(&mut self, cache: &mut PageCache, url: &str, text: String, navigation: Navigation) {
+        let page = gemini_page(text);
+        self.show_page(url, &page, false);
+        self.add_to_history(cache, url, page, false, navigation);
+        self.page = None;
+    }
+
+    /// The link with the number on the shown page, as written and resolved against the page.
+    /// That is the URL in the top line, as a page still loading is not in the history yet.
+    pub fn link_target(&self, num: usize) -> std::result::Result<Option<(String, url::Url)>, String> {
+        let link = match &self.container.links {
+            Some(v) if num >= 1 && v.len() >= num => v[num-1].to_string(),
+            _ => { return Ok(None); }
+        };
+        let parsed = parse_gemini_link(&link, &self.top_line)?;
+        Ok(Some((link, parsed)))
+    }
+}
+
+/// Every open tab in order, with the index of the one shown
+struct Tabs<T> {
+    tabs: Vec<T>,
+    current: usize
+}
+
+impl<T> Tabs<T> {
+    pub fn new(first: T) -> Self {
+        Tabs { tabs: vec![first], current: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tabs.len()
+    }
+
+    /// Index of the shown tab
+    pub fn index(&self) -> usize {
+        self.current
+    }
+
+    pub fn current(&self) -> &T {
+        &self.tabs[self.current]
+    }
+
+    pub fn current_mut(&mut self) -> &mut T {
+        &mut self.tabs[self.current]
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> &mut T {
+        &mut self.tabs[i]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.tabs.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.tabs.iter_mut()
+    }
+
+    /// Shows tab `i`, returning false if it already is or does not exist
+    pub fn switch(&mut self, i: usize) -> bool {
+        if i == self.current || i >= self.tabs.len() {
+            return false;
+        }
+        self.current = i;
+        true
+    }
+
+    /// Adds a tab after the current one and shows it
+    pub fn open(&mut self, tab: T) {
+        self.tabs.insert(self.current + 1, tab);
+        self.current += 1;
+    }
+
+    /// Closes tab `i`, returning it. Closing the shown tab shows the next one, or the
+    /// previous one when it was the last.
+    pub fn close(&mut self, i: usize) -> std::result::Result<T, String> {
+        if i >= self.tabs.len() {
+            return Err(format!("No tab {}", i + 1));
+        }
+        if self.tabs.len() == 1 {
+            return Err("Cannot close the only tab".to_string());
+        }
+
+        let tab = self.tabs.remove(i);
+        if i < self.current || self.current == self.tabs.len() {
+            self.current -= 1;
+        }
+        Ok(tab)
+    }
+
+    /// Moves the shown tab to position `i`
+    pub fn move_current(&mut self, i: usize) -> std::result::Result<(), String> {
+        if i >= self.tabs.len() {
+            return Err(format!("No tab {}", i + 1));
+        }
+        let tab = self.tabs.remove(self.current);
+        self.tabs.insert(i, tab);
+        self.current = i;
+        Ok(())
+    }
+}
+
+pub struct TextUI {
     bottom_line: String,
 
-    history: GeminiHistory,
+    tabs: Tabs<Tab>,
+    /// Shared by all tabs
+    cache: PageCache,
+    request_options: protocol::RequestOptions,
+    downloads: downloads::Manager,
+    downloads_shown_at: Option<Instant>,
 
     quit: bool
 }
@@ -357,21 +834,54 @@ impl TextUI {
 
         let container = ContentContainer::new();
 
+        let cfg = config::get();
+        let request_options = protocol::RequestOptions {
+            connect_timeout: cfg.connect_timeout,
+            read_timeout: cfg.read_timeout,
+            total_timeout: cfg.total_timeout,
+            max_body_size: cfg.max_body_size,
+            ..Default::default()
+        };
+
         Ok(TextUI {
-            top_line: String::new(),
-            container: container,
             bottom_line: String::new(),
-            history: GeminiHistory::new(),
+            tabs: Tabs::new(Tab::new(container)),
+            cache: PageCache::new(cfg.cache_size),
+            request_options,
+            downloads: downloads::Manager::new(),
+            downloads_shown_at: None,
             quit: false
         })
     }
 
+    fn tab(&self) -> &Tab {
+        self.tabs.current()
+    }
+
+    fn tab_mut(&mut self) -> &mut Tab {
+        self.tabs.current_mut()
+    }
+
     pub fn main_loop(&mut self) -> std::result::Result<(), String> {
         loop {
             if self.quit == true {
                 return Ok(());
             }
 
+            if self.tabs.iter().any(|t| t.pending.is_some()) || self.downloads.is_active() {
+                self.poll_request()?;
+                self.poll_background_tabs()?;
+                self.poll_downloads()?;
+
+                let has_event = match event::poll(Duration::from_millis(50)) {
+                    Ok(b) => b,
+                    Err(_) => { return Err("Error polling for events".to_string()); }
+                };
+                if !has_event {
+                    continue;
+                }
+            }
+
             match read().unwrap() {
                 Event::Resize(width, height) => {
                     self.handle_resize_event(width, height)?;
@@ -386,9 +896,11 @@ impl TextUI {
     }
 
     fn handle_resize_event(&mut self, width: u16, height: u16) -> std::result::Result<(), String> {
-        self.container.resize(width, height);
+        for tab in self.tabs.iter_mut() {
+            tab.container.resize(width, height);
+        }
     
-        let scroll = self.container.scroll_pos();
+        let scroll = self.tab().container.scroll_pos();
         self.bottom_line = format!("Scroll: {}, {}", scroll.0, scroll.1);
 
         self.redraw_window()?;
@@ -419,8 +931,19 @@ impl TextUI {
             KeyCode::Char('f') => {
                 self.go_forwards()?;
             },
+            KeyCode::Tab => {
+                self.switch_tab((self.tabs.index() + 1) % self.tabs.len())?;
+            },
+            KeyCode::BackTab => {
+                self.switch_tab((self.tabs.index() + self.tabs.len() - 1) % self.tabs.len())?;
+            },
             KeyCode::Esc => {
-                self.quit = true;
+                if self.cancel_request() {
+                    self.bottom_line = "Request cancelled".to_string();
+                    self.redraw_window()?;
+                } else {
+                    self.quit = self.confirm_quit()?;
+                }
                 return Ok(());
             },
             _ => {}
@@ -440,32 +963,58 @@ impl TextUI {
             },
 
             Some(Command::Quit) => {
-                self.quit = true;
+                self.quit = self.confirm_quit()?;
+                if !self.quit {
+                    self.redraw_window()?;
+                }
                 return Ok(());
             },
 
             Some(Command::Link(num)) => {
-                let mut url: Option<String> = None;
-                match &self.container.links {
-                    Some(v) => {
-                        if v.len() >= num {
-                            url = Some(v[num-1].to_string());
-                        }
-                    },
-                    None => {}
-                }
-                match url {
-                    Some(u) => {
-                        let cur = self.history.get_current_url().unwrap_or("".to_string());
-                        let parsed = parse_gemini_link(&u, &cur)?; 
-                        self.bottom_line = format!("Following link {} to {}", num, u);
-                        self.redraw_window()?;
-                        self.command_go(parsed.as_str())?;                 
-                    }
-                    None => {}
+                if let Some((u, parsed)) = self.link_target(num)? {
+                    self.bottom_line = format!("Following link {} to {}", num, u);
+                    self.redraw_window()?;
+                    self.tab_mut().history.set_link(num);
+                    self.command_go(parsed.as_str())?;
                 }
             },
 
+            Some(Command::Tab(cmd)) => {
+                self.command_tab(cmd)?;
+            },
+
+            Some(Command::Identity(cmd)) => {
+                self.command_identity(cmd)?;
+            },
+
+            Some(Command::Certs(cmd)) => {
+                self.command_certs(cmd)?;
+            },
+
+            Some(Command::Bookmark(cmd)) => {
+                self.command_bookmark(cmd)?;
+            },
+
+            Some(Command::Reload) => {
+                self.command_reload()?;
+            },
+
+            Some(Command::History(term)) => {
+                let url = match term {
+                    Some(t) => format!("about:history?{}", utf8_percent_encode(&t, QUERY_ENCODE_SET)),
+                    None => "about:history".to_string()
+                };
+                self.command_go(&url)?;
+            },
+
+            Some(Command::CancelDownload(id)) => {
+                self.command_cancel_download(id)?;
+            },
+
+            Some(Command::Save { path, rendered }) => {
+                self.command_save(path.as_deref(), rendered)?;
+            },
+
             Some(Command::Unknown(c)) => {
                 print_error = true;
                 error_msg = format!("Unknown command: {}", c);
@@ -486,75 +1035,1070 @@ impl TextUI {
     }
 
     fn command_go(&mut self, url: &str) -> std::result::Result<(), String> {
-        if let Some(cached) = self.history.get_from_cache((&url).to_string()) {
-            match cached {
-                TextPage::Gemini(v) => {
-                    self.container.set_contents_gemini(v);
+        self.navigate(url, Navigation::New)
+    }
+
+    /// Fetches the current page again, bypassing the cache
+    fn command_reload(&mut self) -> std::result::Result<(), String> {
+        match self.tab().history.get_current_url() {
+            Some(url) => self.navigate(&url, Navigation::Reload),
+            None => {
+                self.bottom_line = "No page to reload".to_string();
+                self.redraw_window()
+            }
+        }
+    }
+
+    fn navigate(&mut self, url: &str, navigation: Navigation) -> std::result::Result<(), String> {
+        self.cancel_request();
+
+        // Going back and forwards saves the position before moving in the history
+        if navigation != Navigation::History {
+            self.tab_mut().save_scroll();
+        }
+
+        if navigation == Navigation::History {
+            if let Some((page, fetched)) = self.cache.get(url) {
+                let page = page.clone();
+                self.tab_mut().show_page(url, &page, false);
+                self.tab_mut().restore_position();
+                self.bottom_line = format!("{} (cached {} ago)", url, format_age(fetched.elapsed()));
+                if let Some(link) = self.tab().history.current_entry().and_then(|e| e.link) {
+                    self.bottom_line.push_str(&format!(", last followed link {}", link));
                 }
-                TextPage::Plain(s) => {
-                    self.container.set_contents_text(s);
+                if !url.starts_with("about:") {
+                    self.record_visit(url, page_title(&page).as_deref());
                 }
+                self.tab_mut().page = Some(page);
+                return self.redraw_window();
             }
+            // The history has already moved on from the page that is still shown
+            self.tab_mut().page = None;
+        }
 
-            self.redraw_window()?;
-
-            return Ok(());
+        if url.starts_with("about:") {
+            return self.command_about(url, navigation);
         }
-        let r = match protocol::make_request(&url) {
+
+        // Invalid URLs are passed on as they are, to end up on an error page
+        let url = match protocol::parse_url(url) {
+            Ok(u) => match redirects::lookup(u.as_str()) {
+                Ok(Some(target)) => target,
+                Ok(None) => u.to_string(),
+                Err(e) => {
+                    self.bottom_line = e;
+                    u.to_string()
+                }
+            },
+            Err(_) => url.to_string()
+        };
+
+        self.start_request(&url, Vec::new(), navigation)
+    }
+
+    /// Acts on a finished request
+    fn handle_response(&mut self, mut request: PendingRequest, result: std::result::Result<Response, protocol::Error>) -> std::result::Result<(), String> {
+        let url = request.url.clone();
+        let url = url.as_str();
+        let r = match result {
             Ok(r) => r,
+            Err(protocol::Error::Cancelled) => {
+                self.bottom_line = "Request cancelled".to_string();
+                self.redraw_window()?;
+                return Ok(());
+            }
+            Err(protocol::Error::CertUntrusted { host, port, known_digest, cert }) => {
+                return self.confirm_cert_change(url, &host, port, &known_digest, &cert);
+            }
             Err(e) => {
-                self.bottom_line = e;
+                let page = error_page(url, self.previous_url(url).as_deref(), &e);
+                self.show_generated_page(url, page, request.navigation);
+                self.bottom_line = e.to_string();
                 self.redraw_window()?;
                 return Ok(());
             }
         };
+        let status = r.status();
         match r {
-            Response::Success(mime, contents) => {
+            Response::Success(mime, body) => {
                 if !document::is_text_doc(&mime) {
-                    self.bottom_line = format!("Not a text document: '{}'", mime);
-                    self.redraw_window()?;
-                    return Ok(());
+                    return self.start_download(request, &mime, body);
                 }
 
-                let raw = String::from_utf8(contents).unwrap();
-
-                if !document::is_gemini_doc(&mime) {
-                    self.history.insert(url.to_string(), TextPage::Plain(raw.to_string()));
-                    self.container.set_contents_text(&raw);
-                } else {
-                    let doc = document::parse_gemini_doc(&raw);
-                    self.history.insert(url.to_string(), TextPage::Gemini(doc.clone()));
-                    self.container.set_contents_gemini(&doc);
-                }
-                self.redraw_window()?;
+                request.stream_text(&mime, body);
+                self.tab_mut().pending = Some(request);
+            },
+            Response::Input(prompt) => {
+                self.command_input(url, &prompt, false)?;
             },
-            Response::RedirectPerm(url) | Response::RedirectTemp(url) => {
-                match self.ask_user_yes_no(&format!("Follow redirection? -> {}", url), None) {
-                    Ok(true) => { return self.command_go(&url); }
-                    Ok(false) => {}
-                    Err(e) => { return Err(e); }
+            Response::SensitiveInput(prompt) => {
+                self.command_input(url, &prompt, true)?;
+            },
+            Response::RedirectTemp(target) => {
+                self.follow_redirect(request, &target, false)?;
+            }
+            Response::RedirectPerm(target) => {
+                self.follow_redirect(request, &target, true)?;
+            }
+            Response::SlowDown(meta) => {
+                self.show_status_page(url, status, meta.as_deref(), request.navigation)?;
+
+                let seconds = meta.as_deref().and_then(|m| m.trim().parse::<u64>().ok());
+                if let Some(seconds) = seconds {
+                    let question = format!("Server asked to slow down. Retry automatically in {} s? (y/n)", seconds);
+                    if self.ask_user_yes_no(&question, None)? {
+                        self.retry_after(url, seconds)?;
+                    } else {
+                        self.redraw_window()?;
+                    }
                 }
             }
-            _ => {}
+            Response::ClientCertRequired(meta) => {
+                self.show_status_page(url, status, meta.as_deref(), request.navigation)?;
+                self.choose_identity(url)?;
+            }
+            Response::TemporaryFailure(meta) |
+            Response::ServerUnavailable(meta) |
+            Response::CgiError(meta) |
+            Response::ProxyError(meta) |
+            Response::PermanentFailure(meta) |
+            Response::NotFound(meta) |
+            Response::Gone(meta) |
+            Response::ProxyReqRefused(meta) |
+            Response::BadRequest(meta) |
+            Response::CertNotAuthorized(meta) |
+            Response::CertNotValid(meta) => {
+                self.show_status_page(url, status, meta.as_deref(), request.navigation)?;
+            }
         };
         Ok(())
     }
 
-    fn ask_user_yes_no(&mut self, question: &str, default: Option<bool>) -> std::result::Result<bool, String> {
-        match default {
-            None => {}
-            Some(_) => { return Err("Default not yet implemented".to_string()); }
-        }
+    /// Follows a redirect within the same site, asking first before going to another one
+    fn follow_redirect(&mut self, request: PendingRequest, target: &str, permanent: bool) -> std::result::Result<(), String> {
+        let from = protocol::parse_url(&request.url).map_err(|e| e.to_string())?;
+        let to = match redirects::resolve(&from, target) {
+            Ok(u) => u,
+            Err(e) => {
+                self.bottom_line = e;
+                return self.redraw_window();
+            }
+        };
 
-        let size = terminal::size().unwrap();
-        self.bottom_line = question.to_string();
-        queue!(
-            stdout(),
-            MoveTo(0, size.1),
-            Print(&self.bottom_line),
-            cursor::Show
-        ).unwrap();
-        stdout().flush().unwrap();
+        let mut chain = request.redirects;
+        chain.push(from.to_string());
+
+        match redirects::check(&chain, &from, &to) {
+            redirects::Check::SameSite => {}
+            redirects::Check::OtherSite => {
+                if !self.ask_user_yes_no(&format!("Follow redirect to another site? -> {}", to), None)? {
+                    self.bottom_line = format!("Redirect to {} not followed", to);
+                    return self.redraw_window();
+                }
+            }
+            redirects::Check::Loop => {
+                self.bottom_line = format!("Redirect loop at {}", to);
+                return self.redraw_window();
+            }
+            redirects::Check::TooMany => {
+                self.bottom_line = format!("Too many redirects, stopped before {}", to);
+                return self.redraw_window();
+            }
+        }
+
+        if permanent {
+            if let Err(e) = redirects::remember(from.as_str(), to.as_str()) {
+                self.bottom_line = format!("Could not remember redirect: {}", e);
+            }
+        }
+
+        self.start_request(to.as_str(), chain, request.navigation)
+    }
+
+    fn confirm_cert_change(&mut self, url: &str, host: &str, port: u16, known_digest: &[u8], cert: &certificates::CertInfo) -> std::result::Result<(), String> {
+        let page = cert_change_page(host, port, known_digest, cert);
+        self.tab_mut().container.set_contents_gemini(&document::parse_gemini_doc(&page));
+        self.redraw_window()?;
+
+        match self.ask_user_choice("Certificate changed! Accept (o)nce, accept (p)ermanently or (a)bort?", &['o', 'p', 'a'])? {
+            Some('o') => {
+                self.request_options.accepted_certs.insert((host.to_string(), port), cert.digest.clone());
+            }
+            Some('p') => {
+                if let Err(e) = certificates::replace_in_db(host, port, cert) {
+                    self.bottom_line = format!("Could not store certificate: {}", e);
+                    self.redraw_window()?;
+                    return Ok(());
+                }
+                self.request_options.accepted_certs.remove(&(host.to_string(), port));
+            }
+            _ => {
+                self.bottom_line = format!("Certificate for {} not accepted", host);
+                self.redraw_window()?;
+                return Ok(());
+            }
+        }
+
+        self.command_go(url)
+    }
+
+    fn choose_identity(&mut self, url: &str) -> std::result::Result<(), String> {
+        let existing = match identities::list() {
+            Ok(v) => v.into_iter().map(|i| i.name).collect::<Vec<String>>(),
+            Err(e) => {
+                self.bottom_line = format!("Could not read identities: {}", e);
+                self.redraw_window()?;
+                return Ok(());
+            }
+        };
+
+        let prompt = if existing.is_empty() {
+            "Name for a new identity: ".to_string()
+        } else {
+            format!("Identity to use ({}) or a new name: ", existing.join(", "))
+        };
+        let name = match self.get_input_from_user(&prompt, false) {
+            Ok(n) => n.trim().to_string(),
+            Err(_) => { return Err("Error reading input".to_string()); }
+        };
+        if name.is_empty() {
+            self.bottom_line = "No identity attached".to_string();
+            self.redraw_window()?;
+            return Ok(());
+        }
+
+        let scope = match self.ask_user_choice("Use the identity for the whole (h)ost or this (p)ath only?", &['h', 'p'])? {
+            Some('h') => IdentityScope::Host,
+            Some(_) => IdentityScope::Path,
+            None => {
+                self.bottom_line = "No identity attached".to_string();
+                self.redraw_window()?;
+                return Ok(());
+            }
+        };
+
+        if !existing.contains(&name) {
+            if let Err(e) = identities::create(&name) {
+                self.bottom_line = e;
+                self.redraw_window()?;
+                return Ok(());
+            }
+        }
+
+        if self.attach_identity(&name, url, scope)? {
+            self.navigate(url, Navigation::Reload)?;
+        }
+
+        Ok(())
+    }
+
+    fn attach_identity(&mut self, name: &str, url: &str, scope: IdentityScope) -> std::result::Result<bool, String> {
+        let parsed = protocol::parse_url(url).map_err(|e| e.to_string())?;
+        let scope = match scope {
+            IdentityScope::Host => identities::host_scope(&parsed),
+            IdentityScope::Path => identities::path_scope(&parsed)
+        };
+
+        match identities::attach(name, &scope) {
+            Ok(()) => {
+                self.bottom_line = format!("Identity '{}' attached to {}", name, scope);
+                self.redraw_window()?;
+                Ok(true)
+            }
+            Err(e) => {
+                self.bottom_line = e;
+                self.redraw_window()?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn command_identity(&mut self, cmd: IdentityCommand) -> std::result::Result<(), String> {
+        let current = self.tab().history.get_current_url();
+
+        match cmd {
+            IdentityCommand::List => {
+                match identities::list() {
+                    Ok(list) => {
+                        let page = identities_page(&list);
+                        self.tab_mut().container.set_contents_gemini(&document::parse_gemini_doc(&page));
+                    }
+                    Err(e) => { self.bottom_line = e; }
+                }
+            }
+            IdentityCommand::New(name) => {
+                self.bottom_line = match identities::create(&name) {
+                    Ok(_) => format!("Created identity '{}'", name),
+                    Err(e) => e
+                };
+            }
+            IdentityCommand::Use(name, scope) => {
+                let url = match current {
+                    Some(u) => u,
+                    None => {
+                        self.bottom_line = "No page open to attach the identity to".to_string();
+                        return Ok(());
+                    }
+                };
+                match identities::load(&name) {
+                    Ok(Some(_)) => { self.attach_identity(&name, &url, scope)?; }
+                    Ok(None) => { self.bottom_line = format!("No identity called '{}'", name); }
+                    Err(e) => { self.bottom_line = e; }
+                }
+            }
+            IdentityCommand::Detach => {
+                let url = match current.map(|u| protocol::parse_url(&u)) {
+                    Some(Ok(u)) => u,
+                    _ => {
+                        self.bottom_line = "No page open".to_string();
+                        return Ok(());
+                    }
+                };
+                self.bottom_line = match identities::detach(&url) {
+                    Ok(Some(scope)) => format!("Identity detached from {}", scope),
+                    Ok(None) => "No identity is attached to this page".to_string(),
+                    Err(e) => e
+                };
+            }
+            IdentityCommand::Delete(name) => {
+                self.bottom_line = match identities::delete(&name) {
+                    Ok(true) => format!("Deleted identity '{}'", name),
+                    Ok(false) => format!("No identity called '{}'", name),
+                    Err(e) => e
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    fn show_status_page(&mut self, url: &str, status: StatusCode, meta: Option<&str>, navigation: Navigation) -> std::result::Result<(), String> {
+        let page = status_page(url, self.previous_url(url).as_deref(), status, meta);
+        self.show_generated_page(url, page, navigation);
+        self.bottom_line = format!("{} {}", status as u8, status.description());
+        self.redraw_window()
+    }
+
+    fn show_generated_page(&mut self, url: &str, text: String, navigation: Navigation) {
+        self.tabs.current_mut().show_generated_page(&mut self.cache, url, text, navigation);
+    }
+
+    /// The page to offer going back to from a page generated for the URL
+    fn previous_url(&self, url: &str) -> Option<String> {
+        self.tab().history.get_current_url().filter(|u| u != url)
+    }
+
+    fn retry_after(&mut self, url: &str, seconds: u64) -> std::result::Result<(), String> {
+        let deadline = Instant::now() + Duration::from_secs(seconds);
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            let remaining = deadline - now;
+            self.bottom_line = format!("Retrying in {} s (Esc to cancel)", remaining.as_secs() + 1);
+            self.redraw_window()?;
+
+            let step = std::cmp::min(remaining, Duration::from_secs(1));
+            let has_event = match event::poll(step) {
+                Ok(b) => b,
+                Err(_) => { return Err("Error polling for events".to_string()); }
+            };
+            if !has_event {
+                continue;
+            }
+
+            match read() {
+                Ok(Event::Key(KeyEvent { code: KeyCode::Esc, .. })) => {
+                    self.bottom_line = "Retry cancelled".to_string();
+                    self.redraw_window()?;
+                    return Ok(());
+                }
+                Ok(Event::Resize(width, height)) => {
+                    for tab in self.tabs.iter_mut() {
+                        tab.container.resize(width, height);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => { return Err("Error reading event".to_string()); }
+            }
+        }
+
+        // Replaces the status page that asked to wait
+        self.navigate(url, Navigation::Reload)
+    }
+
+    /// Starts fetching the URL on a worker thread, to be picked up by `poll_request`
+    fn start_request(&mut self, url: &str, redirects: Vec<String>, navigation: Navigation) -> std::result::Result<(), String> {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (progress_tx, progress_rx) = mpsc::channel();
+        let mut options = self.request_options.clone();
+        options.cancel = Some(cancel.clone());
+        let lift_deadline = Arc::new(AtomicBool::new(false));
+        options.lift_deadline = Some(lift_deadline.clone());
+        options.progress = Some(progress_tx);
+
+        let (tx, rx) = mpsc::channel();
+        let worker_tx = tx.clone();
+        let target = url.to_string();
+        thread::spawn(move || {
+            let _ = worker_tx.send(WorkerMessage::Response(protocol::make_request(&target, &options)));
+        });
+
+        self.tab_mut().pending = Some(PendingRequest {
+            url: url.to_string(),
+            cancel,
+            progress: progress_rx,
+            sender: tx,
+            receiver: rx,
+            lift_deadline,
+            page: None,
+            navigation,
+            redirects,
+            parked: None
+        });
+
+        self.bottom_line = format!("Loading {} (Esc to cancel)", url);
+        self.redraw_window()
+    }
+
+    /// Handles whatever the worker threads have sent since the last call
+    fn poll_request(&mut self) -> std::result::Result<(), String> {
+        let mut latest = None;
+
+        // A response that came in while the tab was in the background
+        if let Some(result) = self.tab_mut().pending.as_mut().and_then(|p| p.parked.take()) {
+            if let Some(request) = self.tab_mut().pending.take() {
+                return self.handle_response(request, result);
+            }
+        }
+
+        loop {
+            let pending = match &mut self.tab_mut().pending {
+                Some(p) => p,
+                None => { return Ok(()); }
+            };
+
+            while let Ok(p) = pending.progress.try_recv() {
+                latest = Some(p);
+            }
+
+            match pending.receiver.try_recv() {
+                Ok(WorkerMessage::Response(result)) => {
+                    if let Some(request) = self.tab_mut().pending.take() {
+                        return self.handle_response(request, result);
+                    }
+                }
+                Ok(WorkerMessage::Data(bytes)) => {
+                    pending.take_data(&bytes);
+                }
+                Ok(WorkerMessage::End(error)) => {
+                    return self.finish_page(self.tabs.index(), error);
+                }
+                Err(mpsc::TryRecvError::Empty) => { break; }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.tab_mut().pending = None;
+                    self.bottom_line = "Request thread stopped unexpectedly".to_string();
+                    return self.redraw_window();
+                }
+            }
+        }
+
+        self.render_partial_page()?;
+
+        if let Some(p) = latest {
+            self.bottom_line = format!("{} (Esc to cancel)", progress_message(&p));
+            if self.print_bottom_row().is_err() {
+                return Err("Error when drawing status line".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes in what has arrived for the tabs that are not shown. Text pages load in the
+    /// background, other responses wait for the tab to be shown as they may need the user.
+    fn poll_background_tabs(&mut self) -> std::result::Result<(), String> {
+        let mut finished = false;
+        for i in 0..self.tabs.len() {
+            if i == self.tabs.index() {
+                continue;
+            }
+            loop {
+                let pending = match &mut self.tabs.get_mut(i).pending {
+                    Some(p) if p.parked.is_none() => p,
+                    _ => { break; }
+                };
+                while pending.progress.try_recv().is_ok() {}
+
+                match pending.receiver.try_recv() {
+                    Ok(WorkerMessage::Response(Ok(Response::Success(mime, body)))) if document::is_text_doc(&mime) => {
+                        pending.stream_text(&mime, body);
+                    }
+                    Ok(WorkerMessage::Response(result)) => {
+                        pending.parked = Some(result);
+                    }
+                    Ok(WorkerMessage::Data(bytes)) => {
+                        pending.take_data(&bytes);
+                    }
+                    Ok(WorkerMessage::End(error)) => {
+                        self.finish_page(i, error)?;
+                        finished = true;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => { break; }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        self.tabs.get_mut(i).pending = None;
+                    }
+                }
+            }
+        }
+
+        // The tab bar shows where the finished tabs ended up
+        if finished {
+            self.redraw_window()?;
+        }
+        Ok(())
+    }
+
+    /// Shows the complete lines received so far, at most once every `RENDER_INTERVAL`
+    fn render_partial_page(&mut self) -> std::result::Result<(), String> {
+        let (url, contents, keep_scroll) = match &self.tab().pending {
+            Some(PendingRequest { url, page: Some(page), .. }) if page.changed && page.rendered_at.is_none_or(|t| t.elapsed() >= RENDER_INTERVAL) => {
+                // A line is only rendered once it is complete
+                let end = match page.raw.iter().rposition(|b| *b == b'\n') {
+                    Some(i) => i + 1,
+                    None => { return Ok(()); }
+                };
+                (url.clone(), text_page(&page.raw[..end], page), page.rendered_at.is_some())
+            }
+            _ => { return Ok(()); }
+        };
+
+        self.tab_mut().show_page(&url, &contents, keep_scroll);
+        if let Some(page) = self.tab_mut().pending.as_mut().and_then(|p| p.page.as_mut()) {
+            page.changed = false;
+            page.rendered_at = Some(Instant::now());
+        }
+        self.redraw_window()
+    }
+
+    /// Shows the whole body in tab `i` once it has been read, or as much of it as arrived before an error
+    fn finish_page(&mut self, i: usize, error: Option<protocol::Error>) -> std::result::Result<(), String> {
+        let tab = self.tabs.get_mut(i);
+        let (url, page, navigation) = match tab.pending.take() {
+            Some(PendingRequest { url, page: Some(page), navigation, .. }) => (url, page, navigation),
+            _ => { return Ok(()); }
+        };
+
+        let contents = text_page(&page.raw, &page);
+        tab.show_page(&url, &contents, page.rendered_at.is_some());
+        let title = page_title(&contents);
+        // An incomplete page is shown but not cached, so that it is fetched again later
+        tab.add_to_history(&mut self.cache, &url, contents, error.is_none(), navigation);
+        if navigation != Navigation::New {
+            tab.restore_position();
+        }
+
+        if i != self.tabs.index() {
+            let _ = history::record(&url, title.as_deref());
+            return Ok(());
+        }
+        self.bottom_line = match error {
+            None => format!("{} ({})", url, format_size(page.raw.len())),
+            Some(e) => format!("{}. Page is incomplete.", e)
+        };
+        self.record_visit(&url, title.as_deref());
+        self.redraw_window()
+    }
+
+    /// Adds the page to the persistent history, only reporting a failure on the status line
+    fn record_visit(&mut self, url: &str, title: Option<&str>) {
+        if let Err(e) = history::record(url, title) {
+            self.bottom_line = format!("Could not record the visit: {}", e);
+        }
+    }
+
+    /// Asks what to do with a document that is not text, and hands its body to the
+    /// download manager to be written to a temporary file for its handler or to the download directory
+    fn start_download(&mut self, request: PendingRequest, mime: &str, mut body: protocol::Body) -> std::result::Result<(), String> {
+        let cfg = config::get();
+        let handler = handlers::find(&cfg.handlers, mime).cloned();
+        let choice = match &handler {
+            Some(h) => {
+                let question = format!("'{}' is not text. (o)pen with {}, (s)ave or (c)ancel?", mime, h.command);
+                self.ask_user_choice(&question, &['o', 's', 'c'])?
+            }
+            None => {
+                let question = format!("No handler for '{}'. (s)ave or (c)ancel?", mime);
+                self.ask_user_choice(&question, &['s', 'c'])?
+            }
+        };
+
+        let created = match (choice, handler) {
+            (Some('o'), Some(h)) => handlers::create_temp_file(&request.url).map(|(path, file)| (path, file, Some(h))),
+            (Some('s'), _) => {
+                let name = handlers::file_name(&request.url).unwrap_or_else(|| "download".to_string());
+                let default = downloads::unique_path(&cfg.download_dir, &name);
+                let input = match self.get_input_from_user(&format!("Save as ({}): ", default.display()), false) {
+                    Ok(i) => i,
+                    Err(_) => { return Err("Error reading input".to_string()); }
+                };
+                // Relative names are taken to be in the download directory
+                let path = if input.trim().is_empty() { default } else { cfg.download_dir.join(input.trim()) };
+                let created = match path.parent() {
+                    Some(dir) => fs::create_dir_all(dir),
+                    None => Ok(())
+                };
+                created
+                    .and_then(|_| fs::OpenOptions::new().write(true).create_new(true).open(&path))
+                    .map(|file| (path, file, None))
+            }
+            _ => {
+                request.cancel.store(true, Ordering::Relaxed);
+                self.bottom_line = "Download cancelled".to_string();
+                return self.redraw_window();
+            }
+        };
+
+        let (path, file, handler) = match created {
+            Ok(c) => c,
+            Err(e) => {
+                request.cancel.store(true, Ordering::Relaxed);
+                self.bottom_line = format!("Could not create file: {}", e);
+                return self.redraw_window();
+            }
+        };
+
+        // Downloads are expected to be large and slow, unlike pages
+        body.set_limit(cfg.max_download_size);
+        request.lift_deadline.store(true, Ordering::Relaxed);
+
+        let target = downloads::Target {
+            url: request.url,
+            mime: mime.to_string(),
+            path: path.clone(),
+            open_with: handler
+        };
+        let id = self.downloads.start(target, file, body, request.cancel);
+        self.bottom_line = format!("Download {} to {} started, see about:downloads", id, path.display());
+        self.redraw_window()
+    }
+
+    /// Takes in download progress, showing it on the status line while no page is loading
+    fn poll_downloads(&mut self) -> std::result::Result<(), String> {
+        for id in self.downloads.poll() {
+            self.finish_download(id)?;
+        }
+
+        let due = self.downloads_shown_at.is_none_or(|t| t.elapsed() >= RENDER_INTERVAL);
+        if self.tab().pending.is_none() && self.downloads.is_active() && due {
+            self.bottom_line = downloads_status(&self.downloads);
+            self.downloads_shown_at = Some(Instant::now());
+            if self.print_bottom_row().is_err() {
+                return Err("Error when drawing status line".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens or reports a download once it has ended
+    fn finish_download(&mut self, id: usize) -> std::result::Result<(), String> {
+        let (path, state, handler, received) = match self.downloads.get(id) {
+            Some(d) => (d.path.clone(), d.state.clone(), d.open_with.clone(), d.received),
+            None => { return Ok(()); }
+        };
+
+        let handler = match (state, handler) {
+            (downloads::State::Finished, Some(h)) => h,
+            (downloads::State::Finished, None) => {
+                self.bottom_line = format!("Saved {} ({})", path.display(), format_size(received));
+                return self.redraw_window();
+            }
+            (downloads::State::Failed(e), _) => {
+                self.bottom_line = format!("Download {} failed: {}", id, e);
+                return self.redraw_window();
+            }
+            (_, _) => {
+                self.bottom_line = format!("Download {} cancelled", id);
+                return self.redraw_window();
+            }
+        };
+
+        if handler.needs_terminal {
+            self.suspend_terminal()?;
+            let status = handlers::run(&handler, &path);
+            self.resume_terminal()?;
+            let _ = fs::remove_file(&path);
+
+            self.bottom_line = match status {
+                Ok(s) if s.success() => format!("{} finished", handler.command),
+                Ok(s) => format!("{} failed: {}", handler.command, s),
+                Err(e) => format!("Could not run {}: {}", handler.command, e)
+            };
+        } else {
+            // The file is left for the handler until quitting, as programs like xdg-open return
+            // before the file has been read
+            self.bottom_line = match handlers::spawn_detached(&handler, &path) {
+                Ok(mut child) => {
+                    thread::spawn(move || child.wait());
+                    format!("Opened {} with {}", path.display(), handler.command)
+                }
+                Err(e) => format!("Could not run {}: {}", handler.command, e)
+            };
+        }
+        self.redraw_window()
+    }
+
+    fn command_bookmark(&mut self, cmd: BookmarkCommand) -> std::result::Result<(), String> {
+        let result = match cmd {
+            BookmarkCommand::List => {
+                return self.command_go("about:bookmarks");
+            }
+            BookmarkCommand::Add(title) => {
+                let url = match self.tab().history.get_current_url() {
+                    Some(u) if !u.starts_with("about:") => u,
+                    _ => {
+                        self.bottom_line = "No page open to bookmark".to_string();
+                        return self.redraw_window();
+                    }
+                };
+                let title = title
+                    .or_else(|| self.tab().page.as_ref().and_then(page_title))
+                    .unwrap_or_else(|| url.clone());
+                bookmarks::add(&url, &title).map(|id| format!("Bookmarked '{}' as {}", title, id))
+            }
+            BookmarkCommand::Delete(id) => {
+                bookmarks::delete(id).map(|found| if found { format!("Deleted bookmark {}", id) } else { format!("No bookmark {}", id) })
+            }
+            BookmarkCommand::Rename(id, title) => {
+                bookmarks::rename(id, &title).map(|found| if found { format!("Renamed bookmark {}", id) } else { format!("No bookmark {}", id) })
+            }
+            BookmarkCommand::Tag(id, tags) => {
+                bookmarks::tag(id, &tags).map(|found| if found { format!("Tagged bookmark {}", id) } else { format!("No bookmark {}", id) })
+            }
+        };
+
+        self.bottom_line = match result {
+            Ok(m) | Err(m) => m
+        };
+        // Keep the list up to date when editing it from the bookmarks page
+        let message = self.bottom_line.clone();
+        if self.tab().history.get_current_url().as_deref() == Some("about:bookmarks") {
+            self.command_reload()?;
+        }
+        self.bottom_line = message;
+        self.redraw_window()
+    }
+
+    /// Saves the source of the current page, or with `rendered` the wrapped text on screen.
+    /// Relative paths are taken to be in the download directory.
+    fn command_save(&mut self, path: Option<&str>, rendered: bool) -> std::result::Result<(), String> {
+        let url = match self.tab().history.get_current_url() {
+            Some(u) => u,
+            None => {
+                self.bottom_line = "No page to save".to_string();
+                return self.redraw_window();
+            }
+        };
+        let page = match &self.tab().page {
+            Some(p) => p.clone(),
+            None => {
+                self.bottom_line = "No document to save on this page".to_string();
+                return self.redraw_window();
+            }
+        };
+
+        let download_dir = &config::get().download_dir;
+        let path = match path {
+            Some(p) => download_dir.join(p),
+            None => {
+                let gemini = matches!(page.contents, TextPage::Gemini(_));
+                downloads::unique_path(download_dir, &save_name(&url, gemini, rendered))
+            }
+        };
+        if path.exists() && !self.ask_user_yes_no(&format!("{} exists. Overwrite? (y/n)", path.display()), None)? {
+            self.bottom_line = "Not saved".to_string();
+            return self.redraw_window();
+        }
+
+        let contents = if rendered {
+            self.tab().container.rendered_text().into_bytes()
+        } else {
+            page.raw
+        };
+        let written = match path.parent() {
+            Some(dir) => fs::create_dir_all(dir),
+            None => Ok(())
+        }.and_then(|_| fs::write(&path, &contents));
+
+        self.bottom_line = match written {
+            Ok(()) => format!("Saved {} ({})", path.display(), format_size(contents.len())),
+            Err(e) => format!("Could not save {}: {}", path.display(), e)
+        };
+        self.redraw_window()
+    }
+
+    /// Quitting stops the running downloads, so ask first if there are any
+    fn confirm_quit(&mut self) -> std::result::Result<bool, String> {
+        let running = self.downloads.running().count();
+        if running == 0 {
+            return Ok(true);
+        }
+        let quit = self.ask_user_yes_no(&format!("Downloads still running: {}. Quit anyway? (y/n)", running), None)?;
+        if quit {
+            // Half-written files would look complete
+            self.downloads.stop_all(Duration::from_secs(5));
+        }
+        Ok(quit)
+    }
+
+    fn link_target(&self, num: usize) -> std::result::Result<Option<(String, url::Url)>, String> {
+        self.tab().link_target(num)
+    }
+
+
+    /// Shows tab `i`. A page loading in the tab that is left carries on in the background.
+    fn switch_tab(&mut self, i: usize) -> std::result::Result<(), String> {
+        if !self.tabs.switch(i) {
+            return Ok(());
+        }
+        self.bottom_line = format!("Tab {} of {}", i + 1, self.tabs.len());
+        self.redraw_window()
+    }
+
+    fn command_tab(&mut self, cmd: TabCommand) -> std::result::Result<(), String> {
+        match cmd {
+            TabCommand::New(target) => {
+                // Links are resolved on the page they are on, before leaving it
+                let url = match target.as_deref().map(|t| (t, t.parse::<usize>())) {
+                    Some((_, Ok(num))) => match self.link_target(num)? {
+                        Some((_, parsed)) => Some(parsed.to_string()),
+                        None => {
+                            self.bottom_line = format!("No link {}", num);
+                            return self.redraw_window();
+                        }
+                    },
+                    Some((t, Err(_))) => Some(t.to_string()),
+                    None => None
+                };
+
+                self.tabs.open(Tab::new(ContentContainer::new()));
+                self.bottom_line = format!("Tab {} of {}", self.tabs.index() + 1, self.tabs.len());
+                match url {
+                    Some(u) => self.command_go(&u),
+                    None => self.redraw_window()
+                }
+            }
+            TabCommand::Switch(n) => {
+                if n == 0 || n > self.tabs.len() {
+                    self.bottom_line = format!("No tab {}", n);
+                    return self.redraw_window();
+                }
+                self.switch_tab(n - 1)
+            }
+            TabCommand::Close(n) => {
+                let i = match n {
+                    Some(0) => {
+                        self.bottom_line = "No tab 0".to_string();
+                        return self.redraw_window();
+                    }
+                    Some(n) => n - 1,
+                    None => self.tabs.index()
+                };
+                self.bottom_line = match self.tabs.close(i) {
+                    Ok(mut tab) => {
+                        tab.cancel_request();
+                        format!("Closed tab {}", i + 1)
+                    }
+                    Err(e) => e
+                };
+                self.redraw_window()
+            }
+            TabCommand::Move(n) => {
+                if n == 0 {
+                    self.bottom_line = "No tab 0".to_string();
+                    return self.redraw_window();
+                }
+                self.bottom_line = match self.tabs.move_current(n - 1) {
+                    Ok(()) => format!("Moved tab to {}", n),
+                    Err(e) => e
+                };
+                self.redraw_window()
+            }
+        }
+    }
+
+    fn command_cancel_download(&mut self, id: usize) -> std::result::Result<(), String> {
+        self.bottom_line = if self.downloads.cancel(id) {
+            format!("Cancelling download {}", id)
+        } else {
+            format!("No running download {}", id)
+        };
+        self.redraw_window()
+    }
+
+    /// Gives the terminal back to the shell for a program that needs it
+    fn suspend_terminal(&self) -> std::result::Result<(), String> {
+        execute!(
+            stdout(),
+            style::ResetColor,
+            cursor::Show,
+            terminal::LeaveAlternateScreen
+        ).map_err(|e| e.to_string())?;
+        terminal::disable_raw_mode().map_err(|e| e.to_string())
+    }
+
+    /// Takes the terminal back after `suspend_terminal`
+    fn resume_terminal(&self) -> std::result::Result<(), String> {
+        execute!(stdout(), EnterAlternateScreen).map_err(|e| e.to_string())?;
+        terminal::enable_raw_mode().map_err(|e| e.to_string())?;
+        execute!(stdout(), terminal::Clear(ClearType::All), cursor::Hide).map_err(|e| e.to_string())
+    }
+
+
+    /// Aborts the request loading in the shown tab, if there is one
+    fn cancel_request(&mut self) -> bool {
+        self.tab_mut().cancel_request()
+    }
+
+
+    fn about_page(&self, url: &str) -> std::result::Result<String, String> {
+        match url {
+            "about:certs" => certificates::list_pins().map(|pins| certs_page(&pins)).map_err(|e| e.to_string()),
+            "about:downloads" => Ok(downloads_page(self.downloads.list())),
+            "about:bookmarks" => bookmarks::list().map(|list| bookmarks_page(&list)),
+            "about:history" => history::recent().map(|visits| history_page(&visits, None)),
+            _ if url.starts_with("about:history?") => {
+                let term = percent_encoding::percent_decode_str(&url["about:history?".len()..]).decode_utf8_lossy().to_string();
+                history::search(&term).map(|visits| history_page(&visits, Some(&term)))
+            }
+            _ => Err(format!("Unknown page: {}", url))
+        }
+    }
+
+    fn command_about(&mut self, url: &str, navigation: Navigation) -> std::result::Result<(), String> {
+        match self.about_page(url) {
+            Ok(p) => {
+                let page = gemini_page(p);
+                self.tab_mut().show_page(url, &page, navigation == Navigation::Reload);
+                self.tabs.current_mut().add_to_history(&mut self.cache, url, page, true, navigation);
+            }
+            Err(e) => { self.bottom_line = e; }
+        }
+        self.redraw_window()
+    }
+
+    fn command_certs(&mut self, cmd: CertsCommand) -> std::result::Result<(), String> {
+        match cmd {
+            CertsCommand::List => {
+                return self.command_go("about:certs");
+            }
+            CertsCommand::Show(host) => {
+                let (host, port) = certificates::parse_host_port(&host);
+                match certificates::get_pin(&host, port) {
+                    Ok(Some(pin)) => {
+                        let page = cert_page(&pin);
+                        self.tab_mut().container.set_contents_gemini(&document::parse_gemini_doc(&page));
+                    }
+                    Ok(None) => { self.bottom_line = format!("No certificate pinned for {}:{}", host, port); }
+                    Err(e) => { self.bottom_line = e.to_string(); }
+                }
+            }
+            CertsCommand::Forget(host) => {
+                let (host, port) = certificates::parse_host_port(&host);
+                self.bottom_line = match certificates::forget(&host, port) {
+                    Ok(true) => format!("Forgot certificate for {}:{}", host, port),
+                    Ok(false) => format!("No certificate pinned for {}:{}", host, port),
+                    Err(e) => e.to_string()
+                };
+            }
+            CertsCommand::Import(path) => {
+                self.bottom_line = match certificates::import_pins(Path::new(&path)) {
+                    Ok(n) => format!("Imported {} certificates from {}", n, path),
+                    Err(e) => format!("Import failed: {}", e)
+                };
+            }
+            CertsCommand::Export(path) => {
+                self.bottom_line = match certificates::export_pins(Path::new(&path)) {
+                    Ok(n) => format!("Exported {} certificates to {}", n, path),
+                    Err(e) => format!("Export failed: {}", e)
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    fn command_input(&mut self, url: &str, prompt: &str, sensitive: bool) -> std::result::Result<(), String> {
+        let prompt = if prompt.is_empty() {
+            "Input: ".to_string()
+        } else {
+            format!("{}: ", prompt)
+        };
+
+        let input = match self.get_input_from_user(&prompt, sensitive) {
+            Ok(i) => i,
+            Err(_) => { return Err("Error reading input".to_string()); }
+        };
+
+        if input.is_empty() {
+            self.bottom_line = "Input cancelled".to_string();
+            self.redraw_window()?;
+            return Ok(());
+        }
+
+        let target = add_query(url, &input)?;
+        self.command_go(&target)
+    }
+
+    fn ask_user_choice(&mut self, question: &str, choices: &[char]) -> std::result::Result<Option<char>, String> {
+        let size = terminal::size().unwrap();
+        self.bottom_line = question.to_string();
+        queue!(
+            stdout(),
+            MoveTo(0, size.1),
+            terminal::Clear(ClearType::CurrentLine),
+            Print(&self.bottom_line),
+            cursor::Show
+        ).unwrap();
+        stdout().flush().unwrap();
+
+        let choice = loop {
+            if let Event::Key(event) = read().unwrap() {
+                match event.code {
+                    KeyCode::Char(c) if choices.contains(&c) => { break Some(c); }
+                    KeyCode::Esc => { break None; }
+                    _ => {}
+                }
+            }
+        };
+
+        execute!(
+            stdout(),
+            cursor::Hide
+        ).unwrap();
+        self.bottom_line = "".to_string();
+
+        Ok(choice)
+    }
+
+    fn ask_user_yes_no(&mut self, question: &str, default: Option<bool>) -> std::result::Result<bool, String> {
+        match default {
+            None => {}
+            Some(_) => { return Err("Default not yet implemented".to_string()); }
+        }
+
+        let size = terminal::size().unwrap();
+        self.bottom_line = question.to_string();
+        queue!(
+            stdout(),
+            MoveTo(0, size.1),
+            Print(&self.bottom_line),
+            cursor::Show
+        ).unwrap();
+        stdout().flush().unwrap();
 
         loop {
             match read().unwrap() {
@@ -587,14 +2131,14 @@ impl TextUI {
 
     fn scroll(&mut self, direction: char) -> std::result::Result<(), String> {
         match direction {
-            'l' => self.container.scroll_left(),
-            'r' => self.container.scroll_right(),
-            'u' => self.container.scroll_up(),
-            'd' => self.container.scroll_down(),
+            'l' => self.tab_mut().container.scroll_left(),
+            'r' => self.tab_mut().container.scroll_right(),
+            'u' => self.tab_mut().container.scroll_up(),
+            'd' => self.tab_mut().container.scroll_down(),
             _ => { return Err(format!("Unknown direction given: {}", direction)); }
         }
 
-        let scroll = self.container.scroll_pos();
+        let scroll = self.tab().container.scroll_pos();
         self.bottom_line = format!("Scroll: {}, {}", scroll.0, scroll.1);
         self.redraw_window()?;
 
@@ -611,7 +2155,7 @@ impl TextUI {
             Ok(_) => {},
             Err(_) => { return Err(error_message); }
         }
-        match self.container.print() {
+        match self.tab().container.print() {
             Ok(_) => {},
             Err(_) => { return Err(error_message); }
         }
@@ -623,10 +2167,23 @@ impl TextUI {
     }
 
     fn print_top_row(&self) -> Result<()> {
+        let tab = self.tabs.current();
+        let mut line = match &tab.container.lang {
+            Some(lang) => format!("{} [{}]", tab.top_line, lang),
+            None => tab.top_line.clone()
+        };
+        if self.tabs.len() > 1 {
+            let labels = self.tabs.iter()
+                .map(|t| tab_label(&t.top_line))
+                .collect::<Vec<String>>();
+            line = format!("{}  {}", tab_bar(&labels, self.tabs.index()), line);
+        }
+        // A wrapping row would overwrite the first line of the page
+        let width = terminal::size()?.0 as usize;
         queue!(
             stdout(),
             MoveTo(0, 0),
-            Print(&self.top_line)
+            Print(truncate_to_width(&line, width))
         )?;
         stdout().flush()?;
 
@@ -638,6 +2195,7 @@ impl TextUI {
         queue!(
             stdout(),
             MoveTo(0, size.1),
+            terminal::Clear(ClearType::CurrentLine),
             Print(&self.bottom_line)
         )?;
         stdout().flush()?;
@@ -646,13 +2204,17 @@ impl TextUI {
     }
 
     fn get_command_from_user(&self) -> Result<String> {
+        self.get_input_from_user("> ", false)
+    }
+
+    fn get_input_from_user(&self, prompt: &str, sensitive: bool) -> Result<String> {
         let size = terminal::size()?;
     
         queue!(
             stdout(),
             MoveTo(0, size.1),
             terminal::Clear(ClearType::CurrentLine),
-            Print("> "),
+            Print(prompt),
             cursor::Show
         ).unwrap();
         stdout().flush()?;
@@ -672,7 +2234,11 @@ impl TextUI {
                         KeyCode::Backspace => {
                             if let Some(c) = command.pop() {
                                 let s = c.to_string();
-                                let l = UnicodeWidthStr::width(&s[..]) as u16;
+                                let l = if sensitive {
+                                    1
+                                } else {
+                                    UnicodeWidthStr::width(&s[..]) as u16
+                                };
                                 queue!(
                                     stdout(),
                                     cursor::MoveLeft(l)
@@ -699,7 +2265,7 @@ impl TextUI {
                             command.push(c);
                             execute!(
                                 stdout(),
-                                Print(c),
+                                Print(if sensitive { '*' } else { c }),
                             )?;
                         },
                         _ => {}
@@ -719,9 +2285,10 @@ impl TextUI {
     }
 
     fn go_back(&mut self) -> std::result::Result<(), String> {
-        if self.history.go_back() == true {
-            if let Some(url) = self.history.get_current_url() {
-                self.command_go(&url)?;
+        self.tab_mut().save_scroll();
+        if self.tab_mut().history.go_back() {
+            if let Some(url) = self.tab().history.get_current_url() {
+                self.navigate(&url, Navigation::History)?;
             }
         }
 
@@ -729,9 +2296,10 @@ impl TextUI {
     }
 
     fn go_forwards(&mut self) -> std::result::Result<(), String> {
-        if self.history.go_forwards() == true {
-            if let Some(url) = self.history.get_current_url() {
-                self.command_go(&url)?;
+        self.tab_mut().save_scroll();
+        if self.tab_mut().history.go_forwards() {
+            if let Some(url) = self.tab().history.get_current_url() {
+                self.navigate(&url, Navigation::History)?;
             }
         }
 
@@ -739,6 +2307,161 @@ impl TextUI {
     }
 }
 
+/// Reads the body on its own thread, passing it on in chunks as they arrive
+fn stream_body(mut body: protocol::Body, tx: mpsc::Sender<WorkerMessage>) {
+    let mut chunk = [0u8; 8192];
+    loop {
+        match body.read(&mut chunk) {
+            Ok(0) => {
+                let _ = tx.send(WorkerMessage::End(None));
+                return;
+            }
+            Ok(n) => {
+                if tx.send(WorkerMessage::Data(chunk[..n].to_vec())).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                let _ = tx.send(WorkerMessage::End(Some(e.into())));
+                return;
+            }
+        }
+    }
+}
+
+/// Decodes the bytes of a page that arrived as `partial`, possibly only its beginning
+fn text_page(raw: &[u8], partial: &PartialPage) -> Page {
+    let text = document::decode_text(raw, partial.charset.as_deref());
+    let contents = if partial.gemini {
+        TextPage::Gemini(document::parse_gemini_doc(&text))
+    } else {
+        TextPage::Plain(text)
+    };
+
+    Page {
+        contents,
+        lang: partial.lang.clone(),
+        raw: raw.to_vec()
+    }
+}
+
+fn progress_message(progress: &protocol::Progress) -> String {
+    match progress {
+        protocol::Progress::Resolving(host) => format!("Resolving {}", host),
+        protocol::Progress::Connecting(address) => format!("Connecting to {}", address),
+        protocol::Progress::Handshake => "TLS handshake".to_string(),
+        protocol::Progress::Waiting => "Waiting for response".to_string(),
+        protocol::Progress::Received(bytes) => format!("Received {}", format_size(*bytes))
+    }
+}
+
+fn format_size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// File name for saving the page at `url`, keeping the name from the URL where there is one
+fn save_name(url: &str, gemini: bool, rendered: bool) -> String {
+    let name = handlers::file_name(url).unwrap_or_else(|| "index".to_string());
+    let has_extension = Path::new(&name).extension().is_some();
+
+    if rendered {
+        let stem = Path::new(&name).file_stem().map_or(name.clone(), |s| s.to_string_lossy().to_string());
+        format!("{}.txt", stem)
+    } else if has_extension {
+        name
+    } else if gemini {
+        format!("{}.gmi", name)
+    } else {
+        format!("{}.txt", name)
+    }
+}
+
+/// Short name for a tab showing the URL
+fn tab_label(url: &str) -> String {
+    let label = match Url::parse(url) {
+        Ok(u) if u.host_str().is_some() => u.host_str().unwrap_or_default().to_string(),
+        _ if url.is_empty() => "new tab".to_string(),
+        _ => url.to_string()
+    };
+    label.chars().take(20).collect()
+}
+
+/// Numbered tab labels with the current tab in brackets
+fn tab_bar(labels: &[String], current: usize) -> String {
+    labels.iter().enumerate()
+        .map(|(i, label)| if i == current { format!("[{} {}]", i + 1, label) } else { format!("{} {}", i + 1, label) })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Rough age for the status line, such as "5 min"
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    if secs < 60 {
+        format!("{} s", secs)
+    } else if secs < 3600 {
+        format!("{} min", secs / 60)
+    } else {
+        format!("{} h", secs / 3600)
+    }
+}
+
+fn format_rate(bytes_per_second: f64) -> String {
+    format!("{}/s", format_size(bytes_per_second as usize))
+}
+
+/// Status line summary of the running downloads
+fn downloads_status(downloads: &downloads::Manager) -> String {
+    let running = downloads.running().collect::<Vec<&downloads::Download>>();
+    let received = running.iter().map(|d| d.received).sum::<usize>();
+    let rate = running.iter().map(|d| d.rate()).sum::<f64>();
+    let noun = if running.len() == 1 { "download" } else { "downloads" };
+
+    format!("{} {}: {} at {}", running.len(), noun, format_size(received), format_rate(rate))
+}
+
+fn downloads_page(downloads: &[downloads::Download]) -> String {
+    let mut page = "# Downloads\n\n".to_string();
+    if downloads.is_empty() {
+        page.push_str("Nothing has been downloaded in this session.\n");
+        return page;
+    }
+
+    for d in downloads.iter().rev() {
+        let state = match &d.state {
+            downloads::State::Running => format!("{} at {}", format_size(d.received), format_rate(d.rate())),
+            downloads::State::Finished => format!("done, {} at {}", format_size(d.received), format_rate(d.rate())),
+            downloads::State::Failed(e) => format!("failed: {}", e),
+            downloads::State::Cancelled => "cancelled".to_string()
+        };
+        page.push_str(&format!("## {}. {}\n", d.id, d.path.display()));
+        page.push_str(&format!("=> {} {} ({})\n", d.url, d.url, d.mime));
+        page.push_str(&format!("{}\n\n", state));
+    }
+    page.push_str("Use 'cancel N' to stop download N.\n");
+    page
+}
+
+/// The start of the line that fits in `width` columns
+fn truncate_to_width(line: &str, width: usize) -> String {
+    let mut result = String::new();
+    let mut used = 0;
+    for g in line.graphemes(true) {
+        used += UnicodeWidthStr::width(g);
+        if used > width {
+            break;
+        }
+        result.push_str(g);
+    }
+    result
+}
+
 fn pretty_wrap(line: &str, width: usize) -> Vec::<String> {
     let mut results = Vec::<String>::new();
 
@@ -796,6 +2519,14 @@ fn parse_command(s: &str) -> Option<Command> {
     let go_re = Regex::new(r"^\s*go? +(.+)").unwrap();
     let quit_re = Regex::new(r"^\s*q(uit)?( .*)?").unwrap();
     let link_re = Regex::new(r"^\s*(\d+)\s*").unwrap();
+    let certs_re = Regex::new(r"^\s*certs(?:\s+(\S+))?(?:\s+(.+?))?\s*$").unwrap();
+    let id_re = Regex::new(r"^\s*id(?:\s+(\S+))?(?:\s+(\S+))?(?:\s+(\S+))?\s*$").unwrap();
+    let bm_re = Regex::new(r"^\s*bm(?:\s+(\S+))?(?:\s+(.+?))?\s*$").unwrap();
+    let tab_re = Regex::new(r"^\s*tab(?:\s+(\S+))?(?:\s+(.+?))?\s*$").unwrap();
+    let reload_re = Regex::new(r"^\s*reload\s*$").unwrap();
+    let history_re = Regex::new(r"^\s*history(?:\s+(.+?))?\s*$").unwrap();
+    let save_re = Regex::new(r"^\s*save(?:\s+(-r))?(?:\s+(.+?))?\s*$").unwrap();
+    let cancel_re = Regex::new(r"^\s*cancel\s+(\d+)\s*$").unwrap();
     let generic_re = Regex::new(r"^\s*(\S+)").unwrap();
 
     if go_re.is_match(s) {
@@ -814,6 +2545,106 @@ fn parse_command(s: &str) -> Option<Command> {
         }
         Some(Command::Unknown("".to_string()))
     }
+    else if certs_re.is_match(s) {
+        let groups = certs_re.captures(s).unwrap();
+        let arg = groups.get(2).map(|m| m.as_str().to_string());
+        let cmd = match (groups.get(1).map(|m| m.as_str()), arg) {
+            (None, None) | (Some("list"), None) => Some(CertsCommand::List),
+            (Some("show"), Some(host)) => Some(CertsCommand::Show(host)),
+            (Some("forget"), Some(host)) => Some(CertsCommand::Forget(host)),
+            (Some("import"), Some(path)) => Some(CertsCommand::Import(path)),
+            (Some("export"), Some(path)) => Some(CertsCommand::Export(path)),
+            _ => None
+        };
+        match cmd {
+            Some(c) => Some(Command::Certs(c)),
+            None => Some(Command::Unknown(s.trim().to_string()))
+        }
+    }
+    else if bm_re.is_match(s) {
+        let groups = bm_re.captures(s).unwrap();
+        let rest = groups.get(2).map(|m| m.as_str().to_string());
+        let cmd = match (groups.get(1).map(|m| m.as_str()), rest) {
+            (None, None) | (Some("list"), None) => Some(BookmarkCommand::List),
+            (Some("add"), title) => Some(BookmarkCommand::Add(title)),
+            // The other commands start with the id of the bookmark
+            (Some(sub), Some(rest)) => {
+                let (id, rest) = match rest.split_once(char::is_whitespace) {
+                    Some((id, rest)) => (id, Some(rest.trim_start().to_string())),
+                    None => (rest.as_str(), None)
+                };
+                match (sub, id.parse::<i64>().ok(), rest) {
+                    ("delete", Some(id), None) => Some(BookmarkCommand::Delete(id)),
+                    ("rename", Some(id), Some(title)) => Some(BookmarkCommand::Rename(id, title)),
+                    ("tag", Some(id), tags) => {
+                        let tags = tags.map(|t| t.split_whitespace().map(|t| t.to_string()).collect()).unwrap_or_default();
+                        Some(BookmarkCommand::Tag(id, tags))
+                    }
+                    _ => None
+                }
+            }
+            _ => None
+        };
+        match cmd {
+            Some(c) => Some(Command::Bookmark(c)),
+            None => Some(Command::Unknown(s.trim().to_string()))
+        }
+    }
+    else if tab_re.is_match(s) {
+        let groups = tab_re.captures(s).unwrap();
+        let number = groups.get(2).and_then(|m| m.as_str().parse::<usize>().ok());
+        let cmd = match (groups.get(1).map(|m| m.as_str()), groups.get(2)) {
+            (None, None) | (Some("new"), None) => Some(TabCommand::New(None)),
+            (Some("new"), Some(target)) => Some(TabCommand::New(Some(target.as_str().to_string()))),
+            (Some("close"), None) => Some(TabCommand::Close(None)),
+            (Some("close"), Some(_)) => number.map(|n| TabCommand::Close(Some(n))),
+            (Some("move"), Some(_)) => number.map(TabCommand::Move),
+            (Some(n), None) => n.parse::<usize>().ok().map(TabCommand::Switch),
+            _ => None
+        };
+        match cmd {
+            Some(c) => Some(Command::Tab(c)),
+            None => Some(Command::Unknown(s.trim().to_string()))
+        }
+    }
+    else if reload_re.is_match(s) {
+        Some(Command::Reload)
+    }
+    else if history_re.is_match(s) {
+        let groups = history_re.captures(s).unwrap();
+        Some(Command::History(groups.get(1).map(|m| m.as_str().to_string())))
+    }
+    else if save_re.is_match(s) {
+        let groups = save_re.captures(s).unwrap();
+        Some(Command::Save {
+            path: groups.get(2).map(|m| m.as_str().to_string()),
+            rendered: groups.get(1).is_some()
+        })
+    }
+    else if cancel_re.is_match(s) {
+        let groups = cancel_re.captures(s).unwrap();
+        match groups[1].parse::<usize>() {
+            Ok(id) => Some(Command::CancelDownload(id)),
+            Err(_) => Some(Command::Unknown(s.trim().to_string()))
+        }
+    }
+    else if id_re.is_match(s) {
+        let groups = id_re.captures(s).unwrap();
+        let arg = |i: usize| groups.get(i).map(|m| m.as_str().to_string());
+        let cmd = match (arg(1).as_deref(), arg(2), arg(3).as_deref()) {
+            (None, None, None) | (Some("list"), None, None) => Some(IdentityCommand::List),
+            (Some("new"), Some(name), None) => Some(IdentityCommand::New(name)),
+            (Some("use"), Some(name), None) => Some(IdentityCommand::Use(name, IdentityScope::Path)),
+            (Some("use"), Some(name), Some("host")) => Some(IdentityCommand::Use(name, IdentityScope::Host)),
+            (Some("detach"), None, None) => Some(IdentityCommand::Detach),
+            (Some("delete"), Some(name), None) => Some(IdentityCommand::Delete(name)),
+            _ => None
+        };
+        match cmd {
+            Some(c) => Some(Command::Identity(c)),
+            None => Some(Command::Unknown(s.trim().to_string()))
+        }
+    }
     else {
         if generic_re.is_match(s) {
             let groups = generic_re.captures(s).unwrap();
@@ -845,6 +2676,254 @@ fn parse_gemini_link(url: &str, base: &str) -> std::result::Result<url::Url, Str
     Err("Could not parse URL".to_string())
 }
 
+fn status_page(url: &str, previous: Option<&str>, status: StatusCode, meta: Option<&str>) -> String {
+    let code = status as u8;
+    let explanation = match status {
+        StatusCode::TemporaryFailure => "The request failed, but the same request may succeed in the future.",
+        StatusCode::ServerUnavailable => "The server is unavailable due to overload or maintenance.",
+        StatusCode::CgiError => "A CGI process or similar dynamic content system died or timed out.",
+        StatusCode::ProxyError => "A proxy request failed because the server could not complete a transaction with the remote host.",
+        StatusCode::SlowDown => "The server is rate limiting requests and asks the client to wait before trying again.",
+        StatusCode::PermanentFailure => "The request failed, and future requests for the same resource will fail too.",
+        StatusCode::NotFound => "The requested resource could not be found, but may be available in the future.",
+        StatusCode::Gone => "The requested resource is no longer available and will not be available again.",
+        StatusCode::ProxyReqRefused => "The request was for a resource at a domain not served by the server, and the server does not accept proxy requests.",
+        StatusCode::BadRequest => "The server was unable to parse the request.",
+        StatusCode::ClientCertRequired => "The requested resource requires a client certificate.",
+        StatusCode::CertNotAuthorized => "The supplied client certificate is not authorised for accessing this resource.",
+        StatusCode::CertNotValid => "The supplied client certificate was not accepted because it is not valid.",
+        _ => "The server returned an unexpected status."
+    };
+
+    let mut page = format!("# {} {}\n\n{}\n", code, status.description(), explanation);
+
+    if let Some(m) = meta {
+        if !m.is_empty() {
+            page.push_str(&format!("\nThe server said:\n> {}\n", m));
+        }
+    }
+
+    page.push_str("\n## What you can do\n");
+    match code / 10 {
+        4 => {
+            page.push_str("* This failure is temporary, so trying again later may help.\n");
+        },
+        5 => {
+            page.push_str("* This failure is permanent, so retrying is unlikely to help.\n");
+            if status == StatusCode::NotFound || status == StatusCode::BadRequest {
+                page.push_str("* Check that the address is correct.\n");
+            }
+        },
+        6 => {
+            page.push_str("* Attach a client certificate identity with 'id use <name>' or create one with 'id new <name>', then retry.\n");
+        },
+        _ => {}
+    }
+    page.push_str(&format!("=> {} Retry\n", url));
+    if let Some(p) = previous {
+        page.push_str(&format!("=> {} Go back to the previous page\n", p));
+    }
+
+    page
+}
+
+fn error_page(url: &str, previous: Option<&str>, error: &protocol::Error) -> String {
+    use protocol::Error;
+
+    let explanation = match error {
+        Error::UrlParse(_) => "The address could not be understood. Check it for typos.",
+        Error::UnsupportedScheme(_) => "Only gemini:// addresses can be opened.",
+        Error::Dns { .. } => "The host name could not be resolved. Check the address and your network connection.",
+        Error::Connect { .. } => "The server did not accept the connection. It may be down, or the port may be wrong.",
+        Error::Tls(_) => "A secure connection could not be established with the server.",
+        Error::CertUntrusted { .. } => "The server presented a different certificate from the one seen before.",
+        Error::CertMissing => "The server did not present a certificate, so its identity could not be checked.",
+        Error::Database(_) => "The certificate store could not be read or updated.",
+        Error::Identity(_) => "The client certificate attached to this address could not be used.",
+        Error::HeaderTooLong |
+        Error::BadHeader(_) |
+        Error::InvalidStatus(_) => "The server sent a response that does not follow the Gemini protocol.",
+        Error::Timeout(_) => "The server took too long to respond. It may be overloaded, or the timeouts may be too short.",
+        Error::Cancelled => "The request was cancelled.",
+        Error::TooLarge(_) => "The response was larger than the configured max_body_size.",
+        Error::Io(_) => "The connection failed while talking to the server."
+    };
+
+    let mut page = format!("# {}\n\n{}\n", error, explanation);
+
+    let mut source = std::error::Error::source(error);
+    if source.is_some() {
+        page.push_str("\nDetails:\n");
+    }
+    while let Some(e) = source {
+        page.push_str(&format!("> {}\n", e));
+        source = e.source();
+    }
+
+    page.push('\n');
+    match error {
+        Error::UrlParse(_) | Error::UnsupportedScheme(_) => {},
+        _ => { page.push_str(&format!("=> {} Retry\n", url)); }
+    }
+    if let Some(p) = previous {
+        page.push_str(&format!("=> {} Go back to the previous page\n", p));
+    }
+
+    page
+}
+
+fn cert_change_page(host: &str, port: u16, known_digest: &[u8], cert: &certificates::CertInfo) -> String {
+    let mut page = format!("# Certificate for {}:{} has changed\n\n", host, port);
+    page.push_str("The server presented a different certificate from the one seen before. \
+                   This is expected when a certificate is renewed, but it may also mean that \
+                   someone is intercepting the connection.\n");
+    page.push_str(&format!("\n## Previously seen certificate\n* SHA-256: {}\n", certificates::fingerprint(known_digest)));
+    page.push_str(&format!("\n## New certificate\n* SHA-256: {}\n", certificates::fingerprint(&cert.digest)));
+    page.push_str(&format!("* Subject: {}\n", cert.subject));
+    page.push_str(&format!("* Valid from: {}\n", cert.not_before));
+    page.push_str(&format!("* Valid until: {}\n", cert.not_after));
+
+    page
+}
+
+fn pin_details(pin: &certificates::PinnedCert) -> String {
+    let time = |t: Option<i64>| t.map_or("unknown".to_string(), certificates::format_time);
+
+    format!("* SHA-256: {}\n* Expires: {}\n* First seen: {}\n* Last seen: {}\n",
+            certificates::fingerprint(&pin.digest),
+            time(pin.not_after),
+            time(pin.first_seen),
+            time(pin.last_seen))
+}
+
+fn certs_page(pins: &[certificates::PinnedCert]) -> String {
+    let mut page = "# Pinned certificates\n".to_string();
+
+    if pins.is_empty() {
+        page.push_str("\nNo certificates have been pinned yet.\n");
+    }
+
+    for pin in pins {
+        page.push_str(&format!("\n## {}:{}\n", pin.host, pin.port));
+        page.push_str(&pin_details(pin));
+    }
+
+    page
+}
+
+fn cert_page(pin: &certificates::PinnedCert) -> String {
+    let mut page = format!("# Certificate for {}:{}\n\n", pin.host, pin.port);
+    page.push_str(&pin_details(pin));
+    page.push_str("\n=> about:certs All pinned certificates\n");
+
+    page
+}
+
+/// A generated gemtext page
+fn gemini_page(text: String) -> Page {
+    Page {
+        contents: TextPage::Gemini(document::parse_gemini_doc(&text)),
+        lang: None,
+        raw: text.into_bytes()
+    }
+}
+
+/// The first top level heading of a gemtext page
+fn page_title(page: &Page) -> Option<String> {
+    match &page.contents {
+        TextPage::Gemini(lines) => lines.iter().find_map(|l| match l {
+            Line::Heading1(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+            _ => None
+        }),
+        TextPage::Plain(_) => None
+    }
+}
+
+/// Bookmarks without tags first, then a section for each tag
+fn bookmarks_page(bookmarks: &[bookmarks::Bookmark]) -> String {
+    let mut page = "# Bookmarks\n".to_string();
+    if bookmarks.is_empty() {
+        page.push_str("\nNo bookmarks. Add the current page with 'bm add [title]'.\n");
+        return page;
+    }
+
+    let link = |b: &bookmarks::Bookmark| format!("=> {} {} ({})\n", b.url, b.title, b.id);
+
+    let untagged = bookmarks.iter().filter(|b| b.tags.is_empty()).collect::<Vec<&bookmarks::Bookmark>>();
+    if !untagged.is_empty() {
+        page.push('\n');
+        for b in untagged {
+            page.push_str(&link(b));
+        }
+    }
+
+    let mut tags = bookmarks.iter().flat_map(|b| b.tags.iter()).collect::<Vec<&String>>();
+    tags.sort();
+    tags.dedup();
+    for tag in tags {
+        page.push_str(&format!("\n## {}\n", tag));
+        for b in bookmarks.iter().filter(|b| b.tags.contains(tag)) {
+            page.push_str(&link(b));
+        }
+    }
+
+    page.push_str("\nThe number in parentheses is used with 'bm delete N', 'bm rename N <title>' and 'bm tag N [tags]'.\n");
+    page
+}
+
+/// Visits grouped by day, newest first, either all recent ones or the results of searching for `term`
+fn history_page(visits: &[history::Visit], term: Option<&str>) -> String {
+    let mut page = match term {
+        Some(t) => format!("# History matching '{}'\n", t),
+        None => "# History\n".to_string()
+    };
+    if visits.is_empty() {
+        page.push_str("\nNo visits found.\n");
+        return page;
+    }
+
+    for (day, visits) in history::by_day(visits) {
+        page.push_str(&format!("\n## {}\n", day));
+        for visit in visits {
+            let time = &certificates::format_time(visit.visited)[11..16];
+            page.push_str(&format!("=> {} {} {}\n", visit.url, time, visit.title.as_deref().unwrap_or(&visit.url)));
+        }
+    }
+
+    if visits.len() >= history::MAX_VISITS {
+        page.push_str(&format!("\nOnly the latest {} visits are shown. Use 'history <term>' to search for older ones.\n", history::MAX_VISITS));
+    }
+    page
+}
+
+fn identities_page(identities: &[identities::IdentityInfo]) -> String {
+    let mut page = "# Identities\n".to_string();
+
+    if identities.is_empty() {
+        page.push_str("\nNo identities. Create one with 'id new <name>'.\n");
+    }
+
+    for identity in identities {
+        page.push_str(&format!("\n## {}\n", identity.name));
+        if identity.scopes.is_empty() {
+            page.push_str("Not attached to any address.\n");
+        }
+        for scope in &identity.scopes {
+            page.push_str(&format!("=> {}\n", scope));
+        }
+    }
+
+    page
+}
+
+fn add_query(url: &str, input: &str) -> std::result::Result<String, String> {
+    let mut target = protocol::parse_url(url).map_err(|e| e.to_string())?;
+    let query = utf8_percent_encode(input, QUERY_ENCODE_SET).to_string();
+    target.set_query(Some(&query));
+
+    Ok(target.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -857,5 +2936,399 @@ mod tests {
         assert_eq!(parse_command("not a command"), Some(Command::Unknown("not".to_string())));
         assert_eq!(parse_command("2"), Some(Command::Link(2)));
         assert_eq!(parse_command(" 17 "), Some(Command::Link(17)));
+        assert_eq!(parse_command("id"), Some(Command::Identity(IdentityCommand::List)));
+        assert_eq!(parse_command("id new alice"), Some(Command::Identity(IdentityCommand::New("alice".to_string()))));
+        assert_eq!(parse_command("id use alice"), Some(Command::Identity(IdentityCommand::Use("alice".to_string(), IdentityScope::Path))));
+        assert_eq!(parse_command("id use alice host"), Some(Command::Identity(IdentityCommand::Use("alice".to_string(), IdentityScope::Host))));
+        assert_eq!(parse_command("id delete alice"), Some(Command::Identity(IdentityCommand::Delete("alice".to_string()))));
+        assert_eq!(parse_command("certs"), Some(Command::Certs(CertsCommand::List)));
+        assert_eq!(parse_command("certs list"), Some(Command::Certs(CertsCommand::List)));
+        assert_eq!(parse_command("certs show example.com"), Some(Command::Certs(CertsCommand::Show("example.com".to_string()))));
+        assert_eq!(parse_command("certs forget example.com"), Some(Command::Certs(CertsCommand::Forget("example.com".to_string()))));
+        assert_eq!(parse_command("certs export /tmp/my pins.txt"), Some(Command::Certs(CertsCommand::Export("/tmp/my pins.txt".to_string()))));
+        assert_eq!(parse_command("certs forget"), Some(Command::Unknown("certs forget".to_string())));
+        assert_eq!(parse_command("id frobnicate"), Some(Command::Unknown("id frobnicate".to_string())));
+        assert_eq!(parse_command("cancel 3"), Some(Command::CancelDownload(3)));
+        assert_eq!(parse_command("cancel"), Some(Command::Unknown("cancel".to_string())));
+        assert_eq!(parse_command("bm"), Some(Command::Bookmark(BookmarkCommand::List)));
+        assert_eq!(parse_command("bm add"), Some(Command::Bookmark(BookmarkCommand::Add(None))));
+        assert_eq!(parse_command("bm add My capsule"), Some(Command::Bookmark(BookmarkCommand::Add(Some("My capsule".to_string())))));
+        assert_eq!(parse_command("bm rename 3 New name"), Some(Command::Bookmark(BookmarkCommand::Rename(3, "New name".to_string()))));
+        assert_eq!(parse_command("bm tag 3 news tech"), Some(Command::Bookmark(BookmarkCommand::Tag(3, vec!["news".to_string(), "tech".to_string()]))));
+        assert_eq!(parse_command("bm tag 3"), Some(Command::Bookmark(BookmarkCommand::Tag(3, Vec::new()))));
+        assert_eq!(parse_command("bm delete 4"), Some(Command::Bookmark(BookmarkCommand::Delete(4))));
+        assert_eq!(parse_command("bm delete"), Some(Command::Unknown("bm delete".to_string())));
+        assert_eq!(parse_command("bm add 2024 recap"), Some(Command::Bookmark(BookmarkCommand::Add(Some("2024 recap".to_string())))));
+        assert_eq!(parse_command("bm add 42"), Some(Command::Bookmark(BookmarkCommand::Add(Some("42".to_string())))));
+        assert_eq!(parse_command("bm delete 4 5"), Some(Command::Unknown("bm delete 4 5".to_string())));
+        assert_eq!(parse_command("bm rename x New name"), Some(Command::Unknown("bm rename x New name".to_string())));
+        assert_eq!(parse_command("reload"), Some(Command::Reload));
+        assert_eq!(parse_command("tab"), Some(Command::Tab(TabCommand::New(None))));
+        assert_eq!(parse_command("tab new 4"), Some(Command::Tab(TabCommand::New(Some("4".to_string())))));
+        assert_eq!(parse_command("tab new gemini://example.com/"), Some(Command::Tab(TabCommand::New(Some("gemini://example.com/".to_string())))));
+        assert_eq!(parse_command("tab 2"), Some(Command::Tab(TabCommand::Switch(2))));
+        assert_eq!(parse_command("tab close"), Some(Command::Tab(TabCommand::Close(None))));
+        assert_eq!(parse_command("tab close 3"), Some(Command::Tab(TabCommand::Close(Some(3)))));
+        assert_eq!(parse_command("tab move 1"), Some(Command::Tab(TabCommand::Move(1))));
+        assert_eq!(parse_command("tab move"), Some(Command::Unknown("tab move".to_string())));
+        assert_eq!(parse_command("tab frobnicate"), Some(Command::Unknown("tab frobnicate".to_string())));
+        assert_eq!(parse_command("history"), Some(Command::History(None)));
+        assert_eq!(parse_command("history  gemini news "), Some(Command::History(Some("gemini news".to_string()))));
+        assert_eq!(parse_command("save"), Some(Command::Save { path: None, rendered: false }));
+        assert_eq!(parse_command("save -r"), Some(Command::Save { path: None, rendered: true }));
+        assert_eq!(parse_command("save -r my page.txt "), Some(Command::Save { path: Some("my page.txt".to_string()), rendered: true }));
+        assert_eq!(parse_command("save /tmp/a.gmi"), Some(Command::Save { path: Some("/tmp/a.gmi".to_string()), rendered: false }));
+    }
+
+    #[test]
+    fn error_status_page() {
+        let page = status_page("gemini://example.com/missing", Some("gemini://example.com/"), StatusCode::NotFound, Some("No such file"));
+        let lines = document::parse_gemini_doc(&page);
+        assert!(lines[0] == Line::Heading1("51 Not found".to_string()));
+        assert!(lines.contains(&Line::Quote(" No such file".to_string())));
+        assert!(lines.contains(&Line::Link("gemini://example.com/missing".to_string(), Some("Retry".to_string()))));
+        assert!(lines.contains(&Line::Link("gemini://example.com/".to_string(), Some("Go back to the previous page".to_string()))));
+
+        let page = status_page("gemini://example.com/", None, StatusCode::SlowDown, None);
+        let lines = document::parse_gemini_doc(&page);
+        assert!(lines[0] == Line::Heading1("44 Slow down".to_string()));
+        assert_eq!(lines.iter().filter(|l| matches!(l, Line::Link(_, _))).count(), 1);
+    }
+
+    #[test]
+    fn error_kind_page() {
+        let error = protocol::Error::Connect {
+            address: "example.com:1965".to_string(),
+            source: std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused")
+        };
+        let lines = document::parse_gemini_doc(&error_page("gemini://example.com/", None, &error));
+        assert!(lines[0] == Line::Heading1("Could not connect to example.com:1965".to_string()));
+        assert!(lines.contains(&Line::Quote(" refused".to_string())));
+        assert!(lines.contains(&Line::Link("gemini://example.com/".to_string(), Some("Retry".to_string()))));
+
+        let error = protocol::Error::UnsupportedScheme("https".to_string());
+        let lines = document::parse_gemini_doc(&error_page("https://example.com/", Some("gemini://example.com/"), &error));
+        assert!(lines[0] == Line::Heading1("Scheme 'https' is not supported".to_string()));
+        assert_eq!(lines.iter().filter(|l| matches!(l, Line::Link(_, _))).count(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn input_query() {
+        assert_eq!(add_query("gemini://example.com/search", "hello world?").unwrap(),
+                   "gemini://example.com/search?hello%20world%3F");
+        assert_eq!(add_query("gemini://example.com/search?old", "錆").unwrap(),
+                   "gemini://example.com/search?%E9%8C%86");
+        assert_eq!(add_query("example.com", "a&b=c").unwrap(),
+                   "gemini://example.com/?a%26b%3Dc");
+    }
+
+    #[test]
+    fn progress_messages() {
+        assert_eq!(progress_message(&protocol::Progress::Connecting("example.com:1965".to_string())),
+                   "Connecting to example.com:1965");
+        assert_eq!(progress_message(&protocol::Progress::Received(512)), "Received 512 B");
+        assert_eq!(progress_message(&protocol::Progress::Received(1536)), "Received 1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+        assert_eq!(format_rate(2048.0), "2.0 KiB/s");
+    }
+
+    #[test]
+    fn bookmark_listing() {
+        let bookmark = |id: i64, title: &str, tags: &[&str]| bookmarks::Bookmark {
+            id,
+            url: format!("gemini://example.com/{}", id),
+            title: title.to_string(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            added: 0
+        };
+        let page = bookmarks_page(&[bookmark(1, "One", &[]), bookmark(2, "Two", &["b", "a"]), bookmark(3, "Three", &["a"])]);
+        let lines = document::parse_gemini_doc(&page);
+
+        // Bookmarks with several tags are listed under each of them
+        let links = lines.iter().filter_map(|l| match l {
+            Line::Link(url, _) => Some(url.as_str()),
+            _ => None
+        }).collect::<Vec<&str>>();
+        assert_eq!(links, vec!["gemini://example.com/1", "gemini://example.com/2", "gemini://example.com/3", "gemini://example.com/2"]);
+        assert!(page.contains("## a\n=> gemini://example.com/2 Two (2)\n"));
+
+        let page = Page {
+            contents: TextPage::Gemini(document::parse_gemini_doc("Intro\n# Title \n# Other\n")),
+            lang: None,
+            raw: Vec::new()
+        };
+        assert_eq!(page_title(&page).as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn history_listing() {
+        let visit = |url: &str, title: Option<&str>, visited: i64| history::Visit {
+            url: url.to_string(),
+            title: title.map(|t| t.to_string()),
+            visited
+        };
+        let visits = vec![
+            visit("gemini://example.com/b", Some("B"), 86400 + 3600),
+            visit("gemini://example.com/a", None, 60)
+        ];
+        assert_eq!(history_page(&visits, Some("example")),
+                   "# History matching 'example'\n\n## 1970-01-02\n=> gemini://example.com/b 01:00 B\n\n## 1970-01-01\n=> gemini://example.com/a 00:01 gemini://example.com/a\n");
+        assert!(history_page(&[], None).contains("No visits found"));
+    }
+
+    fn cached_page(size: usize) -> Page {
+        Page {
+            contents: TextPage::Plain(String::new()),
+            lang: None,
+            raw: vec![b'x'; size]
+        }
+    }
+
+    #[test]
+    fn page_cache_eviction() {
+        let mut cache = PageCache::new(Some(250));
+        cache.insert("a", cached_page(100));
+        cache.insert("b", cached_page(100));
+        assert!(cache.get("a").is_some());
+
+        // b is the least recently used
+        cache.insert("c", cached_page(100));
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.total_bytes, 200);
+
+        // Replacing an entry does not count it twice
+        cache.insert("c", cached_page(50));
+        assert_eq!(cache.total_bytes, 150);
+
+        // A page larger than the whole cache is still kept until the next one
+        cache.insert("huge", cached_page(1000));
+        assert!(cache.get("huge").is_some());
+        assert_eq!(cache.entries.len(), 1);
+
+        let mut unbounded = PageCache::new(None);
+        for i in 0..100 {
+            unbounded.insert(&i.to_string(), cached_page(1000));
+        }
+        assert_eq!(unbounded.entries.len(), 100);
+    }
+
+    #[test]
+    fn history_positions() {
+        let mut history = GeminiHistory::new();
+        assert!(!history.go_back());
+        assert!(!history.go_forwards());
+
+        history.push("gemini://example.com/".to_string());
+        history.set_scroll((40, 2));
+        history.set_link(7);
+        history.push("gemini://example.com/7".to_string());
+        history.set_scroll((3, 0));
+
+        assert!(history.go_back());
+        assert_eq!(history.current_entry(), Some(&HistoryEntry { url: "gemini://example.com/".to_string(), scroll: (40, 2), link: Some(7) }));
+        assert!(history.go_forwards());
+        assert_eq!(history.current_entry().unwrap().scroll, (3, 0));
+        assert!(!history.go_forwards());
+
+        // A new page replaces what could be gone forwards to
+        assert!(history.go_back());
+        history.push("gemini://example.com/8".to_string());
+        assert_eq!(history.current_entry().unwrap().scroll, (0, 0));
+        assert!(!history.go_forwards());
+        assert_eq!(history.entries.len(), 2);
+    }
+
+    #[test]
+    fn generated_pages() {
+        let mut cache = PageCache::new(None);
+        let mut tab = Tab::new(ContentContainer::with_size(80, 24));
+        let home = "gemini://example.com/";
+        let missing = "gemini://example.com/missing";
+
+        let page = gemini_page("# Home\n".to_string());
+        tab.show_page(home, &page, false);
+        tab.add_to_history(&mut cache, home, page, true, Navigation::New);
+
+        let text = status_page(missing, Some(home), StatusCode::NotFound, None);
+        tab.show_generated_page(&mut cache, missing, text, Navigation::New);
+        assert_eq!(tab.top_line, missing);
+        assert!(tab.page.is_none());
+        assert!(cache.get(missing).is_none());
+        assert_eq!(tab.history.get_current_url().as_deref(), Some(missing));
+
+        // Going back leaves the error page and finds the page before it
+        assert!(tab.history.go_back());
+        assert_eq!(tab.history.get_current_url().as_deref(), Some(home));
+        assert!(cache.get(home).is_some());
+        assert!(tab.history.go_forwards());
+    }
+
+    #[test]
+    fn error_pages_in_history() {
+        let mut cache = PageCache::new(None);
+        let mut tab = Tab::new(ContentContainer::with_size(80, 24));
+        let home = "gemini://example.com/";
+        let down = "gemini://down.example.com/";
+        tab.add_to_history(&mut cache, down, gemini_page("# Down\n".to_string()), true, Navigation::New);
+        tab.add_to_history(&mut cache, home, gemini_page("# Home\n".to_string()), true, Navigation::New);
+        assert!(tab.history.go_back());
+
+        // Going back to a page that can no longer be fetched shows the error in its entry
+        let error = protocol::Error::Connect {
+            address: "down.example.com:1965".to_string(),
+            source: std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused")
+        };
+        tab.show_generated_page(&mut cache, down, error_page(down, None, &error), Navigation::History);
+        assert_eq!(tab.top_line, down);
+        assert!(tab.page.is_none());
+        assert!(cache.get(down).is_none());
+        assert_eq!(tab.history.entries.len(), 2);
+
+        // Retrying replaces the error page instead of adding another step
+        tab.show_generated_page(&mut cache, down, error_page(down, None, &error), Navigation::Reload);
+        assert_eq!(tab.history.entries.len(), 2);
+        assert!(tab.history.go_forwards());
+        assert_eq!(tab.history.get_current_url().as_deref(), Some(home));
+    }
+
+    #[test]
+    fn links_on_loading_page() {
+        let mut cache = PageCache::new(None);
+        let mut tab = Tab::new(ContentContainer::with_size(80, 24));
+        tab.add_to_history(&mut cache, "gemini://example.com/", gemini_page("# Home\n".to_string()), true, Navigation::New);
+
+        // Shown while loading, before it has been added to the history
+        let page = gemini_page("=> next.gmi Next\n".to_string());
+        tab.show_page("gemini://example.org/dir/", &page, false);
+        let (link, target) = tab.link_target(1).unwrap().unwrap();
+        assert_eq!(link, "next.gmi");
+        assert_eq!(target.as_str(), "gemini://example.org/dir/next.gmi");
+        assert!(tab.link_target(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn redirects_in_history() {
+        let mut cache = PageCache::new(None);
+        let mut tab = Tab::new(ContentContainer::with_size(80, 24));
+        for url in &["gemini://example.com/a", "gemini://example.com/old", "gemini://example.com/c"] {
+            tab.add_to_history(&mut cache, url, gemini_page("# Page\n".to_string()), false, Navigation::New);
+        }
+        assert!(tab.history.go_back());
+
+        // Not cached, and now redirecting
+        tab.add_to_history(&mut cache, "gemini://example.com/new", gemini_page("# New\n".to_string()), true, Navigation::History);
+        let urls: Vec<&str> = tab.history.entries.iter().map(|e| e.url.as_str()).collect();
+        assert_eq!(urls, vec!["gemini://example.com/a", "gemini://example.com/new", "gemini://example.com/c"]);
+        assert!(tab.history.go_forwards());
+    }
+
+    fn named_tabs(names: &[&str]) -> Tabs<String> {
+        let mut tabs = Tabs::new(names[0].to_string());
+        for name in &names[1..] {
+            tabs.open(name.to_string());
+        }
+        tabs.switch(0);
+        tabs
+    }
+
+    fn tab_order(tabs: &Tabs<String>) -> Vec<&str> {
+        tabs.iter().map(|t| t.as_str()).collect()
+    }
+
+    #[test]
+    fn closing_tabs() {
+        // The last tab, when shown
+        let mut tabs = named_tabs(&["a", "b", "c"]);
+        tabs.switch(2);
+        assert_eq!(tabs.close(2).unwrap(), "c");
+        assert_eq!(tabs.current(), "b");
+        assert_eq!(tab_order(&tabs), vec!["a", "b"]);
+
+        // A shown tab in the middle gives way to the next one
+        let mut tabs = named_tabs(&["a", "b", "c"]);
+        tabs.switch(1);
+        tabs.close(1).unwrap();
+        assert_eq!(tabs.current(), "c");
+        assert_eq!(tabs.index(), 1);
+
+        // A tab before the shown one
+        let mut tabs = named_tabs(&["a", "b", "c"]);
+        tabs.switch(2);
+        tabs.close(0).unwrap();
+        assert_eq!(tabs.current(), "c");
+        assert_eq!(tab_order(&tabs), vec!["b", "c"]);
+
+        // A tab after the shown one
+        let mut tabs = named_tabs(&["a", "b", "c"]);
+        tabs.close(2).unwrap();
+        assert_eq!(tabs.current(), "a");
+        assert_eq!(tab_order(&tabs), vec!["a", "b"]);
+
+        assert_eq!(tabs.close(5), Err("No tab 6".to_string()));
+        let mut tabs = named_tabs(&["a"]);
+        assert_eq!(tabs.close(0), Err("Cannot close the only tab".to_string()));
+        assert_eq!(tabs.current(), "a");
+    }
+
+    #[test]
+    fn moving_tabs() {
+        let mut tabs = named_tabs(&["a", "b", "c"]);
+        tabs.move_current(2).unwrap();
+        assert_eq!(tabs.index(), 2);
+        assert_eq!(tab_order(&tabs), vec!["b", "c", "a"]);
+
+        tabs.switch(1);
+        tabs.move_current(0).unwrap();
+        assert_eq!(tabs.current(), "c");
+        assert_eq!(tab_order(&tabs), vec!["c", "b", "a"]);
+
+        tabs.move_current(0).unwrap();
+        assert_eq!(tab_order(&tabs), vec!["c", "b", "a"]);
+        assert!(tabs.move_current(3).is_err());
+        assert!(!tabs.switch(0));
+        assert!(!tabs.switch(3));
+
+        tabs.open("d".to_string());
+        assert_eq!(tabs.current(), "d");
+        assert_eq!(tab_order(&tabs), vec!["c", "d", "b", "a"]);
+    }
+
+    #[test]
+    fn tab_labels() {
+        assert_eq!(tab_label("gemini://example.com/some/page"), "example.com");
+        assert_eq!(tab_label("about:bookmarks"), "about:bookmarks");
+        assert_eq!(tab_label(""), "new tab");
+        assert_eq!(tab_label("gemini://a-very-long-host-name.example.com/"), "a-very-long-host-nam");
+
+        let labels = vec!["example.com".to_string(), "new tab".to_string(), "about:history".to_string()];
+        assert_eq!(tab_bar(&labels, 1), "1 example.com [2 new tab] 3 about:history");
+
+        assert_eq!(truncate_to_width("1 example.com [2 new tab]", 13), "1 example.com");
+        assert_eq!(truncate_to_width("[1 日本語]", 6), "[1 日");
+        assert_eq!(truncate_to_width("short", 80), "short");
+    }
+
+    #[test]
+    fn page_ages() {
+        assert_eq!(format_age(Duration::from_secs(5)), "5 s");
+        assert_eq!(format_age(Duration::from_secs(300)), "5 min");
+        assert_eq!(format_age(Duration::from_secs(7300)), "2 h");
+    }
+
+    #[test]
+    fn save_names() {
+        assert_eq!(save_name("gemini://example.com/", true, false), "index.gmi");
+        assert_eq!(save_name("gemini://example.com/notes/today", false, false), "today.txt");
+        assert_eq!(save_name("gemini://example.com/log.gmi?x=1", true, false), "log.gmi");
+        assert_eq!(save_name("gemini://example.com/log.gmi", true, true), "log.txt");
+        assert_eq!(save_name("about:certs", true, false), "index.gmi");
+    }
+
+    #[test]
+    fn download_listing() {
+        let downloads = downloads::Manager::new();
+        assert!(downloads_page(downloads.list()).contains("Nothing has been downloaded"));
+        assert_eq!(downloads_status(&downloads), "0 downloads: 0 B at 0 B/s");
+    }
+}
@@ -0,0 +1,207 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use ruostepurkki::config;
+use ruostepurkki::identities;
+use ruostepurkki::protocol::{self, Error, RequestOptions, Response};
+
+static INIT: Once = Once::new();
+static SERVERS: AtomicUsize = AtomicUsize::new(0);
+/// Servers still running, the data directory is removed when the last one stops
+static LIVE_SERVERS: Mutex<usize> = Mutex::new(0);
+
+/// Keeps pins and identities made by the tests out of the user's database
+fn init_config() {
+    INIT.call_once(|| {
+        let data_dir = env::temp_dir().join(format!("ruostepurkki-it-{}", std::process::id()));
+        let configuration = config::Config::new(data_dir);
+        config::prepare_data_dir(&configuration).unwrap();
+        config::init(configuration).unwrap();
+    });
+}
+
+struct TestServer {
+    child: Child,
+    root: PathBuf,
+    port: u16
+}
+
+impl TestServer {
+    fn start(extra_args: &[&str]) -> Self {
+        init_config();
+        {
+            let mut live = LIVE_SERVERS.lock().unwrap();
+            if *live == 0 {
+                config::prepare_data_dir(config::get()).unwrap();
+            }
+            *live += 1;
+        }
+
+        let n = SERVERS.fetch_add(1, Ordering::SeqCst);
+        let root = env::temp_dir().join(format!("ruostepurkki-it-root-{}-{}", std::process::id(), n));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.gmi"), "# Test capsule\n=> page.txt A page\n").unwrap();
+        fs::write(root.join("page.txt"), "Plain text\n").unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_gemini-testserver"))
+            .arg("--root").arg(&root)
+            .arg("--port").arg("0")
+            .args(extra_args)
+            .stdout(Stdio::piped())
+            // Probes that close without a request would be logged in the test output
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let port = line.trim().rsplit(':').next().unwrap().parse::<u16>().unwrap();
+
+        TestServer { child, root, port }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("gemini://127.0.0.1:{}{}", self.port, path)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.root);
+
+        let mut live = LIVE_SERVERS.lock().unwrap_or_else(|e| e.into_inner());
+        *live -= 1;
+        if *live == 0 {
+            let _ = fs::remove_dir_all(&config::get().data_dir);
+        }
+    }
+}
+
+fn body_of(response: Response) -> Result<String, Error> {
+    match response {
+        Response::Success(_, mut body) => {
+            let mut contents = String::new();
+            body.read_to_string(&mut contents)?;
+            Ok(contents)
+        }
+        _ => panic!("expected a success response")
+    }
+}
+
+#[test]
+fn serves_files() {
+    let server = TestServer::start(&[]);
+    let options = RequestOptions::default();
+
+    match protocol::make_request(&server.url("/"), &options).unwrap() {
+        Response::Success(mime, mut body) => {
+            assert_eq!(mime, "text/gemini");
+            let mut contents = String::new();
+            body.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "# Test capsule\n=> page.txt A page\n");
+        }
+        _ => panic!("expected the index page")
+    }
+
+    let response = protocol::make_request(&server.url("/page.txt"), &options).unwrap();
+    assert_eq!(body_of(response).unwrap(), "Plain text\n");
+
+    let response = protocol::make_request(&server.url("/missing.gmi"), &options).unwrap();
+    assert!(matches!(response, Response::NotFound(_)));
+    let response = protocol::make_request(&server.url("/%2E%2E%2Findex.gmi"), &options).unwrap();
+    assert!(matches!(response, Response::NotFound(_)));
+}
+
+#[test]
+fn scripted_statuses() {
+    let server = TestServer::start(&[]);
+    let options = RequestOptions::default();
+
+    match protocol::make_request(&server.url("/status/44?5"), &options).unwrap() {
+        Response::SlowDown(meta) => assert_eq!(meta.as_deref(), Some("5")),
+        _ => panic!("expected slow down")
+    }
+    match protocol::make_request(&server.url("/status/31?gemini://example.com/"), &options).unwrap() {
+        Response::RedirectPerm(target) => assert_eq!(target, "gemini://example.com/"),
+        _ => panic!("expected a permanent redirect")
+    }
+    let response = protocol::make_request(&server.url("/status/10?Your%20name"), &options).unwrap();
+    assert!(matches!(response, Response::Input(ref p) if p == "Your name"));
+
+    let result = protocol::make_request(&server.url("/status/99"), &options);
+    assert!(matches!(result, Err(Error::InvalidStatus(_))));
+}
+
+#[test]
+fn huge_bodies_are_limited() {
+    let server = TestServer::start(&[]);
+    let options = RequestOptions {
+        max_body_size: Some(100_000),
+        ..Default::default()
+    };
+
+    let response = protocol::make_request(&server.url("/huge/100000"), &options).unwrap();
+    assert_eq!(body_of(response).unwrap().len(), 100_000);
+
+    let response = protocol::make_request(&server.url("/huge/1000000"), &options).unwrap();
+    assert!(matches!(body_of(response), Err(Error::TooLarge(100_000))));
+}
+
+#[test]
+fn slow_bodies_time_out() {
+    let server = TestServer::start(&[]);
+    let options = RequestOptions {
+        read_timeout: Some(Duration::from_millis(500)),
+        ..Default::default()
+    };
+
+    let response = protocol::make_request(&server.url("/slow/2/100"), &options).unwrap();
+    assert_eq!(body_of(response).unwrap(), "Line 1\nLine 2\n");
+
+    let response = protocol::make_request(&server.url("/slow/2/2000"), &options).unwrap();
+    assert!(matches!(body_of(response), Err(Error::Timeout(_))));
+}
+
+#[test]
+fn changed_certificates_are_rejected() {
+    let server = TestServer::start(&["--rotate-certs"]);
+    let mut options = RequestOptions::default();
+
+    // The first certificate is pinned, the next connection presents another one
+    assert!(protocol::make_request(&server.url("/"), &options).is_ok());
+    let (digest, host, port) = match protocol::make_request(&server.url("/"), &options) {
+        Err(Error::CertUntrusted { cert, host, port, .. }) => (cert.digest.clone(), host, port),
+        _ => panic!("expected the changed certificate to be rejected")
+    };
+    assert_eq!((host.as_str(), port), ("127.0.0.1", server.port));
+
+    // Accepting one certificate does not accept the next rotation
+    options.accepted_certs.insert((host, port), digest);
+    assert!(matches!(protocol::make_request(&server.url("/"), &options), Err(Error::CertUntrusted { .. })));
+}
+
+#[test]
+fn client_certificates() {
+    let server = TestServer::start(&[]);
+    let options = RequestOptions::default();
+    let url = server.url("/private");
+
+    let response = protocol::make_request(&url, &options).unwrap();
+    assert!(matches!(response, Response::ClientCertRequired(_)));
+
+    let name = format!("tester-{}", server.port);
+    identities::create(&name).unwrap();
+    identities::attach(&name, &identities::host_scope(&protocol::parse_url(&url).unwrap())).unwrap();
+
+    let response = protocol::make_request(&url, &options).unwrap();
+    let fingerprint = body_of(response).unwrap();
+    assert_eq!(fingerprint.trim().split(':').count(), 32);
+}